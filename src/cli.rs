@@ -0,0 +1,456 @@
+use std::{collections::HashMap, path::Path, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    config::{default_config_path, load_config, Profile, DEFAULT_NAME_TEMPLATE},
+    duplicates::{DuplicateCheck, KeeperPolicy, KeeperRule},
+    models::{Config, FileTypes, ImportMode},
+    similar::DEFAULT_MAX_DISTANCE,
+    template::{validate_layout, validate_name_template},
+    transfer::Preserve,
+    trash::DEFAULT_RETENTION_DAYS,
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Sort images, videos and documents into a dated library",
+    long_about = "Sort images, videos and documents into a dated library.\n\n\
+                  Folders and options can be stored as named profiles in a TOML config file, \
+                  values given on the command line override the profile.\n\n\
+                  Exit codes: 0 on success, 1 when a command finds problems \
+                  (e.g. verify reports missing files), 2 on invalid arguments or configuration."
+)]
+pub struct Cli {
+    /// Config file with named profiles [default: ~/.config/rust_file_sorter/config.toml]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Profile from the config file to use instead of its `default_profile`
+    #[arg(short, long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Print log messages on the console: -v for info, -vv for debug, -vvv for trace
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Folder for the rotating log file [default: ~/.local/state/rust_file_sorter/logs]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Tidy up the library, then import every new file from the source folders
+    Sort {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        library: LibraryArgs,
+        /// Whether new files are copied or moved out of the source folders [default: copy]
+        #[arg(long, value_enum)]
+        mode: Option<ImportMode>,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Hash every supported file in the given folders and print the index
+    Index {
+        /// Folders to index
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+        #[command(flatten)]
+        exts: ExtArgs,
+    },
+    /// Rename and deduplicate the files already in the library
+    Dedupe {
+        #[command(flatten)]
+        library: LibraryArgs,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Print the detected type, datetime and hash of individual files
+    Inspect {
+        /// Files to inspect
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        #[command(flatten)]
+        exts: ExtArgs,
+    },
+    /// Execute a plan written with --plan-out, after checking its sources are unchanged
+    Apply {
+        /// Plan file (JSON, or CSV when it ends in `.csv`)
+        plan: PathBuf,
+        /// Where to record completed operations, a new file [default: next to the plan file]
+        #[arg(long, value_name = "FILE")]
+        journal: Option<PathBuf>,
+        /// Library root whose `.sorter/trash` receives deleted files (required if the plan deletes)
+        #[arg(long, value_name = "DIR")]
+        library_root: Option<PathBuf>,
+        /// Remove deleted files for good instead of moving them to the trash
+        #[arg(long)]
+        permanent_delete: bool,
+        /// Compare every copy with its source byte for byte before it is put in place
+        #[arg(long)]
+        verify_copies: bool,
+        /// What copies take over from their source (can be repeated or comma separated)
+        /// [default: times, mode, owner, xattrs]
+        #[arg(long, value_enum, value_name = "ITEM", value_delimiter = ',')]
+        preserve: Vec<Preserve>,
+        /// Set the modification time of copied and moved files to their metadata datetime
+        #[arg(long)]
+        mtime_from_metadata: bool,
+    },
+    /// Permanently remove trashed files older than the retention period
+    PurgeTrash {
+        #[command(flatten)]
+        library: LibraryArgs,
+        /// Remove trash older than this many days [default: profile `trash_retention_days` or 30]
+        #[arg(long, value_name = "DAYS")]
+        older_than_days: Option<u32>,
+        /// Remove all trashed files, regardless of age
+        #[arg(long, conflicts_with = "older_than_days")]
+        all: bool,
+    },
+    /// Reverse the operations recorded in a journal
+    Undo {
+        /// Journal file written by sort, dedupe or apply
+        journal: PathBuf,
+    },
+    /// Check that every supported file in the source folders is in the library
+    Verify {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        library: LibraryArgs,
+    },
+    /// Report clusters of visually similar images in the library, e.g. resized or recompressed copies
+    Similar {
+        #[command(flatten)]
+        library: LibraryArgs,
+        /// Most bits in which the perceptual hashes of two similar images may differ (0-64)
+        #[arg(
+            long,
+            value_name = "BITS",
+            default_value_t = DEFAULT_MAX_DISTANCE,
+            value_parser = clap::value_parser!(u32).range(0..=64)
+        )]
+        max_distance: u32,
+    },
+}
+
+#[derive(Args)]
+pub struct SourceArgs {
+    /// Folder to import files from (can be repeated)
+    #[arg(short, long = "source", value_name = "DIR")]
+    pub source_dirs: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Print the planned actions without touching any files
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Write the planned actions to a JSON (or `.csv`) file for review instead of executing them
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    pub plan_out: Option<PathBuf>,
+    /// Also write the run report (counts, coverage and failed files) to this JSON file
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+    /// Where to record completed operations, a new file
+    /// [default: <library root>/.sorter/journals/]
+    #[arg(long, value_name = "FILE")]
+    pub journal: Option<PathBuf>,
+    /// Remove deleted files for good instead of moving them to `<library root>/.sorter/trash`
+    #[arg(long)]
+    pub permanent_delete: bool,
+    /// Compare every copy with its source byte for byte before it is put in place
+    #[arg(long)]
+    pub verify_copies: bool,
+}
+
+#[derive(Args)]
+pub struct LibraryArgs {
+    /// Destination folder for documents
+    #[arg(long, value_name = "DIR")]
+    pub doc_dir: Option<String>,
+    /// Destination folder for images
+    #[arg(long, value_name = "DIR")]
+    pub image_dir: Option<String>,
+    /// Destination folder for videos
+    #[arg(long, value_name = "DIR")]
+    pub video_dir: Option<String>,
+    /// Name of dated media: `{field}` or `{field:width}` placeholders (year, month, month_name,
+    /// day, hour, minute, second, subsec, stem, camera_make, camera_model, type, prefix, hash,
+    /// seq) and `strftime` specifiers in the text around them [default: {prefix}_%Y%m%d_%H%M%S]
+    #[arg(long, value_name = "TEMPLATE")]
+    pub name_template: Option<String>,
+    /// Folders below the destination folder that dated media and documents are sorted into,
+    /// e.g. `{year}/{month:02}-{month_name}` [default: none]
+    #[arg(long, value_name = "TEMPLATE")]
+    pub layout: Option<String>,
+    /// Rule for picking the file to keep from a group of duplicates, applied in the order given
+    /// (can be repeated) [default: preferred-folder, has-date, no-copy-suffix, oldest-mtime, shortest-path]
+    #[arg(long = "keep", value_enum, value_name = "RULE")]
+    pub keeper_rules: Vec<KeeperRule>,
+    /// Folder whose files are preferred when picking the file to keep (can be repeated)
+    #[arg(long = "prefer", value_name = "DIR")]
+    pub preferred_folders: Vec<String>,
+    /// How files with matching hashes are confirmed identical before duplicates are deleted [default: bytes]
+    #[arg(long, value_enum, value_name = "CHECK")]
+    pub confirm_duplicates: Option<DuplicateCheck>,
+    /// Also confirm duplicates before new files are skipped, not just before deletes
+    #[arg(long, overrides_with = "no_confirm_skips")]
+    pub confirm_skips: bool,
+    /// Only confirm duplicates before deletes, even if the profile sets `confirm_skips`
+    #[arg(long, overrides_with = "confirm_skips")]
+    pub no_confirm_skips: bool,
+    /// What copies take over from their source, replacing the default list (can be repeated
+    /// or comma separated) [default: times, mode, owner, xattrs]
+    #[arg(long, value_enum, value_name = "ITEM", value_delimiter = ',')]
+    pub preserve: Vec<Preserve>,
+    /// Set the modification time of copied and moved files to their metadata datetime
+    #[arg(long, overrides_with = "no_mtime_from_metadata")]
+    pub mtime_from_metadata: bool,
+    /// Keep the modification time of the source, even if the profile sets `mtime_from_metadata`
+    #[arg(long, overrides_with = "mtime_from_metadata")]
+    pub no_mtime_from_metadata: bool,
+    /// Ignore the library index and hash every library file again
+    #[arg(long)]
+    pub rebuild_index: bool,
+    #[command(flatten)]
+    pub exts: ExtArgs,
+}
+
+#[derive(Args)]
+pub struct ExtArgs {
+    /// Map a file extension to a file type, e.g. `heic=image` (can be repeated)
+    #[arg(long = "ext", value_name = "EXT=TYPE", value_parser = parse_ext_mapping)]
+    pub ext_mappings: Vec<(String, FileTypes)>,
+    /// Only use the extensions given with --ext instead of extending the defaults
+    #[arg(long)]
+    pub no_default_exts: bool,
+}
+
+impl Cli {
+    /// Loads the selected profile, or an empty one when there is no config file to load from.
+    pub fn load_profile(&self) -> Result<Profile, String> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => match default_config_path() {
+                Some(path) if path.is_file() => path,
+                _ if self.profile.is_some() => {
+                    return Err("no config file found to load the profile from".to_owned())
+                }
+                _ => return Ok(Profile::default()),
+            },
+        };
+        let mut config_file = load_config(&path)?;
+        let name = match self
+            .profile
+            .as_ref()
+            .or(config_file.default_profile.as_ref())
+        {
+            Some(name) => name.clone(),
+            None => return Ok(Profile::default()),
+        };
+        return config_file
+            .profiles
+            .remove(&name)
+            .ok_or_else(|| format!("{}: no profile named `{}`", path.display(), name));
+    }
+}
+
+impl SourceArgs {
+    pub fn resolve(&self, profile: &Profile) -> Result<Vec<String>, String> {
+        let source_dirs = match self.source_dirs.is_empty() {
+            true => profile.sources.clone(),
+            false => self.source_dirs.clone(),
+        };
+        if source_dirs.is_empty() {
+            return Err(
+                "no source folders: pass --source or set `sources` in the profile".to_owned(),
+            );
+        }
+        for dir in &source_dirs {
+            if !Path::new(dir).is_dir() {
+                return Err(format!("source folder `{}` does not exist", dir));
+            }
+        }
+        return Ok(source_dirs);
+    }
+}
+
+impl RunArgs {
+    pub fn apply_to(self, config: &mut Config) {
+        config.dry_run = self.dry_run;
+        config.plan_out = self.plan_out;
+        config.journal = self.journal;
+        config.report_out = self.report;
+        config.permanent_delete = self.permanent_delete;
+        config.verify_copies = self.verify_copies;
+    }
+}
+
+impl LibraryArgs {
+    pub fn to_config(
+        &self,
+        profile: &Profile,
+        source_dirs: Vec<String>,
+        import_mode: Option<ImportMode>,
+    ) -> Result<Config, String> {
+        let name_template = self
+            .name_template
+            .clone()
+            .or_else(|| profile.name_template.clone())
+            .unwrap_or_else(|| DEFAULT_NAME_TEMPLATE.to_owned());
+        validate_name_template(&name_template).map_err(|e| format!("--name-template: {}", e))?;
+        let layout = self
+            .layout
+            .clone()
+            .or_else(|| profile.layout.clone())
+            .unwrap_or_default();
+        validate_layout(&layout).map_err(|e| format!("--layout: {}", e))?;
+        return Ok(Config {
+            source_dirs: source_dirs
+                .into_iter()
+                .map(absolute_dir)
+                .collect::<Result<_, _>>()?,
+            doc_dir: absolute_dir(required_dir(
+                &self.doc_dir,
+                &profile.doc_dir,
+                "doc-dir",
+                "doc_dir",
+            )?)?,
+            image_dir: absolute_dir(required_dir(
+                &self.image_dir,
+                &profile.image_dir,
+                "image-dir",
+                "image_dir",
+            )?)?,
+            video_dir: absolute_dir(required_dir(
+                &self.video_dir,
+                &profile.video_dir,
+                "video-dir",
+                "video_dir",
+            )?)?,
+            file_exts: self.exts.file_exts(profile),
+            import_mode: import_mode
+                .or(profile.import_mode)
+                .unwrap_or(ImportMode::Copy),
+            name_template,
+            layout,
+            dry_run: false,
+            plan_out: None,
+            journal: None,
+            report_out: None,
+            permanent_delete: false,
+            verify_copies: false,
+            preserve: match self.preserve.is_empty() {
+                true => profile
+                    .preserve
+                    .clone()
+                    .unwrap_or_else(|| Preserve::ALL.to_vec()),
+                false => self.preserve.clone(),
+            },
+            mtime_from_metadata: flag(
+                self.mtime_from_metadata,
+                self.no_mtime_from_metadata,
+                profile.mtime_from_metadata,
+            ),
+            trash_retention_days: profile
+                .trash_retention_days
+                .unwrap_or(DEFAULT_RETENTION_DAYS),
+            keeper_policy: self.keeper_policy(profile),
+            duplicate_check: self
+                .confirm_duplicates
+                .or(profile.confirm_duplicates)
+                .unwrap_or(DuplicateCheck::Bytes),
+            confirm_skips: flag(
+                self.confirm_skips,
+                self.no_confirm_skips,
+                profile.confirm_skips,
+            ),
+            rebuild_index: self.rebuild_index,
+        });
+    }
+
+    fn keeper_policy(&self, profile: &Profile) -> KeeperPolicy {
+        let rules = match self.keeper_rules.is_empty() {
+            true => profile
+                .keep
+                .clone()
+                .unwrap_or_else(KeeperPolicy::default_rules),
+            false => self.keeper_rules.clone(),
+        };
+        let preferred_folders = match self.preferred_folders.is_empty() {
+            true => &profile.preferred_folders,
+            false => &self.preferred_folders,
+        };
+        return KeeperPolicy {
+            rules,
+            preferred_folders: preferred_folders.iter().map(PathBuf::from).collect(),
+        };
+    }
+}
+
+impl ExtArgs {
+    pub fn file_exts(&self, profile: &Profile) -> HashMap<String, FileTypes> {
+        let mut file_exts = HashMap::new();
+        if !self.no_default_exts {
+            if !profile.no_default_exts {
+                file_exts = Config::default_file_exts();
+            }
+            file_exts.extend(profile.extensions.clone());
+        }
+        file_exts.extend(self.ext_mappings.iter().cloned());
+        return file_exts;
+    }
+}
+
+/// Journals, plans and the index record paths below these folders, so they must not depend
+/// on the working folder of the run.
+fn absolute_dir(dir: String) -> Result<String, String> {
+    let path = std::path::absolute(&dir).map_err(|e| format!("{}: {}", dir, e))?;
+    return path
+        .into_os_string()
+        .into_string()
+        .map_err(|_| format!("{}: not valid UTF-8", dir));
+}
+
+/// A switch given as `--x` or `--no-x`, or else taken from the profile. Off by default.
+fn flag(on: bool, off: bool, profile_value: Option<bool>) -> bool {
+    return match (on, off) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => profile_value.unwrap_or(false),
+    };
+}
+
+fn required_dir(
+    arg: &Option<String>,
+    profile_value: &Option<String>,
+    flag: &str,
+    key: &str,
+) -> Result<String, String> {
+    return arg
+        .clone()
+        .or_else(|| profile_value.clone())
+        .ok_or_else(|| {
+            format!(
+                "missing destination folder: pass --{} or set `{}` in the profile",
+                flag, key
+            )
+        });
+}
+
+fn parse_ext_mapping(input: &str) -> Result<(String, FileTypes), String> {
+    let (ext, file_type) = input
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=TYPE, got `{}`", input))?;
+    let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+    if ext.is_empty() {
+        return Err(format!("missing extension in `{}`", input));
+    }
+    let file_type = FileTypes::from_str(file_type.trim(), true)?;
+    return Ok((ext, file_type));
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    duplicates::{DuplicateCheck, KeeperRule},
+    models::{FileTypes, ImportMode},
+    template::{validate_layout, validate_name_template},
+    transfer::Preserve,
+};
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{prefix}_%Y%m%d_%H%M%S";
+
+/// Contents of `config.toml`: a set of named profiles and the one to use when none is given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub sources: Vec<String>,
+    pub doc_dir: Option<String>,
+    pub image_dir: Option<String>,
+    pub video_dir: Option<String>,
+    #[serde(default)]
+    pub extensions: HashMap<String, FileTypes>,
+    #[serde(default)]
+    pub no_default_exts: bool,
+    pub name_template: Option<String>,
+    pub layout: Option<String>,
+    pub import_mode: Option<ImportMode>,
+    pub trash_retention_days: Option<u32>,
+    pub keep: Option<Vec<KeeperRule>>,
+    #[serde(default)]
+    pub preferred_folders: Vec<String>,
+    pub confirm_duplicates: Option<DuplicateCheck>,
+    pub confirm_skips: Option<bool>,
+    pub preserve: Option<Vec<Preserve>>,
+    pub mtime_from_metadata: Option<bool>,
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    return Some(config_dir.join("rust_file_sorter").join("config.toml"));
+}
+
+/// Loads and validates a config file. Errors are prefixed with the file and the offending key.
+pub fn load_config(path: &Path) -> Result<ConfigFile, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut config: ConfigFile =
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    validate_config(&mut config).map_err(|e| format!("{}: {}", path.display(), e))?;
+    return Ok(config);
+}
+
+fn validate_config(config: &mut ConfigFile) -> Result<(), String> {
+    if let Some(name) = &config.default_profile {
+        if !config.profiles.contains_key(name) {
+            return Err(format!("default_profile: no profile named `{}`", name));
+        }
+    }
+    for (name, profile) in config.profiles.iter_mut() {
+        let key = format!("profiles.{}", name);
+        for (field, dir) in [
+            ("doc_dir", &mut profile.doc_dir),
+            ("image_dir", &mut profile.image_dir),
+            ("video_dir", &mut profile.video_dir),
+        ] {
+            if let Some(dir) = dir {
+                if dir.trim().is_empty() {
+                    return Err(format!("{}.{}: must not be empty", key, field));
+                }
+                *dir = expand_home(dir);
+            }
+        }
+        for (field, dirs) in [
+            ("sources", &mut profile.sources),
+            ("preferred_folders", &mut profile.preferred_folders),
+        ] {
+            for (i, dir) in dirs.iter_mut().enumerate() {
+                if dir.trim().is_empty() {
+                    return Err(format!("{}.{}[{}]: must not be empty", key, field, i));
+                }
+                *dir = expand_home(dir);
+            }
+        }
+        if let Some(rules) = &profile.keep {
+            if rules.is_empty() {
+                return Err(format!("{}.keep: must list at least one rule", key));
+            }
+        }
+        let mut extensions = HashMap::new();
+        for (ext, file_type) in &profile.extensions {
+            let normalized = ext.trim_start_matches('.').to_ascii_lowercase();
+            if normalized.is_empty() || normalized.contains(|c: char| c == '.' || c.is_whitespace())
+            {
+                return Err(format!(
+                    "{}.extensions.{}: not a valid file extension",
+                    key, ext
+                ));
+            }
+            extensions.insert(normalized, *file_type);
+        }
+        profile.extensions = extensions;
+        if let Some(template) = &profile.name_template {
+            validate_name_template(template)
+                .map_err(|e| format!("{}.name_template: {}", key, e))?;
+        }
+        if let Some(layout) = &profile.layout {
+            validate_layout(layout).map_err(|e| format!("{}.layout: {}", key, e))?;
+        }
+    }
+    return Ok(());
+}
+
+fn expand_home(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = env::var_os("HOME") {
+            return PathBuf::from(home).join(rest).display().to_string();
+        }
+    }
+    return path.to_owned();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(contents: &str) -> Result<ConfigFile, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, contents).unwrap();
+        return load_config(&path).map_err(|e| e.replace(&format!("{}: ", path.display()), ""));
+    }
+
+    #[test]
+    fn profiles_are_loaded_and_normalized() {
+        let config = load(
+            r#"
+            default_profile = "phone"
+
+            [profiles.phone]
+            sources = ["/mnt/usb/DCIM"]
+            image_dir = "/library/image"
+            import_mode = "move"
+            layout = "{year}/{month:02}"
+            keep = ["has-date", "shortest-path"]
+            preserve = ["times"]
+
+            [profiles.phone.extensions]
+            ".HEIC" = "image"
+            "#,
+        )
+        .unwrap();
+        let profile = &config.profiles["phone"];
+        assert_eq!(config.default_profile.as_deref(), Some("phone"));
+        assert_eq!(profile.sources, ["/mnt/usb/DCIM"]);
+        assert_eq!(profile.image_dir.as_deref(), Some("/library/image"));
+        assert_eq!(profile.import_mode, Some(ImportMode::Move));
+        assert_eq!(profile.extensions["heic"], FileTypes::IMAGE);
+        assert_eq!(profile.preserve, Some(vec![Preserve::Times]));
+    }
+
+    #[test]
+    fn invalid_profiles_name_the_offending_key() {
+        for (contents, error) in [
+            (
+                "default_profile = \"x\"",
+                "default_profile: no profile named `x`",
+            ),
+            (
+                "[profiles.p]\ndoc_dir = \" \"",
+                "profiles.p.doc_dir: must not be empty",
+            ),
+            (
+                "[profiles.p]\nsources = [\"/a\", \"\"]",
+                "profiles.p.sources[1]: must not be empty",
+            ),
+            (
+                "[profiles.p]\nkeep = []",
+                "profiles.p.keep: must list at least one rule",
+            ),
+            (
+                "[profiles.p.extensions]\n\"tar.gz\" = \"document\"",
+                "profiles.p.extensions.tar.gz: not a valid file extension",
+            ),
+            (
+                "[profiles.p]\nname_template = \"{nope}\"",
+                "profiles.p.name_template: ",
+            ),
+            ("[profiles.p]\nlayout = \"{day:99}\"", "profiles.p.layout: "),
+        ] {
+            let message = load(contents).err().unwrap();
+            assert!(message.starts_with(error), "{}", message);
+        }
+        assert!(load("[profiles.p]\nimage_folder = \"/a\"").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    error::FileResult, hashing::ContentReader, metadata_parser::datetime_parser::read_datetime,
+    models::FileIndex,
+};
+
+/// Files sharing the same content hash. The keeper stays (or is imported), the duplicates
+/// are deleted from the library or skipped when importing.
+pub struct DuplicateGroup {
+    pub hash: u64,
+    pub keeper: PathBuf,
+    pub duplicates: Vec<PathBuf>,
+    /// Files with the same hash whose content turned out to differ from the keeper.
+    pub collisions: Vec<PathBuf>,
+}
+
+/// How files with matching hashes are confirmed to be identical before one is dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateCheck {
+    /// Trust the 64-bit content hash
+    Off,
+    /// Compare the hashed content byte by byte
+    Bytes,
+    /// Compare BLAKE3 hashes of the hashed content
+    Blake3,
+}
+
+/// Whether two files have the same content, compared over the same bytes the content hash
+/// covers so files differing only in metadata are identical. Files that cannot be read are
+/// never identical, so nothing is dropped on the strength of an I/O error.
+pub fn files_identical(a: &Path, b: &Path, check: DuplicateCheck) -> bool {
+    let size = |path: &Path| ContentReader::open(path).map(|r| r.len()).ok();
+    let same_size = || size(a).is_some() && size(a) == size(b);
+    return match check {
+        DuplicateCheck::Off => true,
+        DuplicateCheck::Bytes => same_size() && compare_bytes(a, b).unwrap_or(false),
+        DuplicateCheck::Blake3 => {
+            same_size()
+                && matches!((blake3_digest(a), blake3_digest(b)), (Some(a), Some(b)) if a == b)
+        }
+    };
+}
+
+/// Whether two files are identical byte for byte, metadata included.
+pub fn files_byte_identical(a: &Path, b: &Path) -> FileResult<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    return compare_readers(File::open(a)?, File::open(b)?);
+}
+
+fn compare_bytes(a: &Path, b: &Path) -> FileResult<bool> {
+    return compare_readers(ContentReader::open(a)?, ContentReader::open(b)?);
+}
+
+fn compare_readers<A: Read, B: Read>(a: A, b: B) -> FileResult<bool> {
+    let mut reader_a = BufReader::new(a);
+    let mut reader_b = BufReader::new(b);
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let read = reader_a.read(&mut buf_a)?;
+        if read == 0 {
+            // Sizes are equal, so the other file has to be exhausted as well
+            return Ok(reader_b.read(&mut buf_b[..1])? == 0);
+        }
+        reader_b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+fn blake3_digest(path: &Path) -> Option<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(ContentReader::open(path).ok()?).ok()?;
+    return Some(hasher.finalize());
+}
+
+/// A preference used to pick the keeper of a duplicate group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeeperRule {
+    /// Prefer files under one of the preferred folders, in the order they are given
+    PreferredFolder,
+    /// Prefer files with a datetime in their metadata
+    HasDate,
+    /// Prefer names without a copy suffix such as `img(1).jpg`
+    NoCopySuffix,
+    /// Prefer the file with the oldest modification time
+    OldestMtime,
+    /// Prefer the shortest path
+    ShortestPath,
+}
+
+/// Rules are applied in order, each one only breaking ties left by the previous rules.
+/// Files that are still tied are ordered by path.
+pub struct KeeperPolicy {
+    pub rules: Vec<KeeperRule>,
+    pub preferred_folders: Vec<PathBuf>,
+}
+
+impl KeeperPolicy {
+    pub fn default_rules() -> Vec<KeeperRule> {
+        return vec![
+            KeeperRule::PreferredFolder,
+            KeeperRule::HasDate,
+            KeeperRule::NoCopySuffix,
+            KeeperRule::OldestMtime,
+            KeeperRule::ShortestPath,
+        ];
+    }
+
+    pub fn select_keeper<'a>(&self, paths: &'a [PathBuf]) -> &'a PathBuf {
+        if paths.len() == 1 {
+            return &paths[0];
+        }
+        return paths
+            .iter()
+            .map(|path| (self.rank(path), path))
+            .min()
+            .map(|(_, path)| path)
+            .unwrap();
+    }
+
+    /// Lower is better for every rule.
+    fn rank(&self, path: &Path) -> Vec<u128> {
+        return self
+            .rules
+            .iter()
+            .map(|rule| match rule {
+                KeeperRule::PreferredFolder => {
+                    self.preferred_folders
+                        .iter()
+                        .position(|dir| path.starts_with(dir))
+                        .unwrap_or(self.preferred_folders.len()) as u128
+                }
+                KeeperRule::HasDate => read_datetime(path).ok().flatten().is_none() as u128,
+                KeeperRule::NoCopySuffix => has_copy_suffix(path) as u128,
+                KeeperRule::OldestMtime => fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_nanos())
+                    .unwrap_or(u128::MAX),
+                KeeperRule::ShortestPath => path.as_os_str().len() as u128,
+            })
+            .collect();
+    }
+}
+
+/// Whether the file name ends in a copy counter, e.g. `img(1).jpg` or `img (2).jpg`.
+fn has_copy_suffix(path: &Path) -> bool {
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem,
+        None => return false,
+    };
+    return match stem.strip_suffix(')').and_then(|s| s.rsplit_once('(')) {
+        Some((name, count)) => {
+            !name.trim_end().is_empty()
+                && !count.is_empty()
+                && count.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    };
+}
+
+pub fn find_duplicate_groups(
+    files: &FileIndex,
+    policy: &KeeperPolicy,
+    check: DuplicateCheck,
+) -> Vec<DuplicateGroup> {
+    let mut groups: Vec<DuplicateGroup> = files
+        .par_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        // Files sharing a content key are fully hashed, everything else is unique
+        .filter_map(|(key, paths)| Some((key.full_hash()?, paths)))
+        .map(|(hash, paths)| {
+            let keeper = policy.select_keeper(paths);
+            let (mut duplicates, mut collisions): (Vec<PathBuf>, Vec<PathBuf>) = paths
+                .iter()
+                .filter(|p| *p != keeper)
+                .cloned()
+                .partition(|p| files_identical(keeper, p, check));
+            duplicates.sort();
+            collisions.sort();
+            DuplicateGroup {
+                hash,
+                keeper: keeper.clone(),
+                duplicates,
+                collisions,
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.keeper.cmp(&b.keeper));
+    return groups;
+}
+
+/// Prints a one-line summary of the duplicate groups, followed by every group when `detailed`.
+pub fn report_duplicates(groups: &[DuplicateGroup], existing: bool, detailed: bool) {
+    for group in groups {
+        for collision in &group.collisions {
+            println!(
+                "Warning: {} has the same hash as {} but different content, keeping both",
+                collision.display(),
+                group.keeper.display()
+            );
+        }
+    }
+    let groups: Vec<&DuplicateGroup> = groups.iter().filter(|g| !g.duplicates.is_empty()).collect();
+    if groups.is_empty() {
+        return;
+    }
+    let (location, resolution) = match existing {
+        true => ("in the library", "to delete"),
+        false => ("among new files", "skipped"),
+    };
+    let num_duplicates: usize = groups.iter().map(|g| g.duplicates.len()).sum();
+    println!(
+        "Found {} duplicate groups {} ({} files {})",
+        groups.len(),
+        location,
+        num_duplicates,
+        resolution
+    );
+    if !detailed {
+        return;
+    }
+    for group in groups {
+        println!("  {:016x}", group.hash);
+        println!("    keep  {}", group.keeper.display());
+        for duplicate in &group.duplicates {
+            println!("    drop  {}", duplicate.display());
+        }
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn has_copy_suffix_matches_numbered_copies() {
+        assert!(has_copy_suffix(Path::new("a/IMG_0001 (1).jpg")));
+        assert!(has_copy_suffix(Path::new("IMG_0001(12).jpg")));
+        assert!(has_copy_suffix(Path::new("notes (2)")));
+    }
+
+    #[test]
+    fn has_copy_suffix_ignores_other_names() {
+        assert!(!has_copy_suffix(Path::new("IMG_0001.jpg")));
+        assert!(!has_copy_suffix(Path::new("(1).jpg")));
+        assert!(!has_copy_suffix(Path::new("IMG_0001 ().jpg")));
+        assert!(!has_copy_suffix(Path::new("IMG_0001 (a).jpg")));
+        assert!(!has_copy_suffix(Path::new("IMG_0001 (1)b.jpg")));
+    }
+}
@@ -0,0 +1,36 @@
+use std::{fmt, io};
+
+/// Why a single file could not be read, hashed or parsed. These never abort a run, they
+/// are collected into its report instead.
+#[derive(Debug)]
+pub enum FileError {
+    /// The file ended before a structure it announced.
+    Truncated,
+    /// A marker that the format requires was not found.
+    BadSignature(&'static str),
+    /// The file uses a structure the parsers do not handle.
+    Unsupported(String),
+    Io(io::Error),
+}
+
+pub type FileResult<T> = Result<T, FileError>;
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            FileError::Truncated => write!(f, "file is truncated"),
+            FileError::BadSignature(expected) => write!(f, "bad signature, expected {}", expected),
+            FileError::Unsupported(what) => write!(f, "unsupported format: {}", what),
+            FileError::Io(e) => write!(f, "{}", e),
+        };
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => FileError::Truncated,
+            _ => FileError::Io(e),
+        };
+    }
+}
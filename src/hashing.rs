@@ -0,0 +1,219 @@
+use std::{
+    cmp::min,
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    error::{FileError, FileResult},
+    metadata_parser::essence_parser::essence_ranges,
+    util::io::StepableBuffReader,
+};
+
+/// Bytes read from each end of the content for a partial hash.
+const PARTIAL_HASH_SIZE: u64 = 16 * 1024;
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hashes the content of a file, leaving out metadata where the format is understood: JPEG
+/// from the start of scan, PNG header, palette and image data, QuickTime/ISO media data and TIFF
+/// strips with the tags needed to decode them.
+/// Other files are hashed whole.
+pub(crate) fn get_file_hash(path: &Path) -> FileResult<u64> {
+    if let Some(ranges) = essence_ranges(path) {
+        return hash_ranges(path, &ranges);
+    }
+    let mut reader = StepableBuffReader::new(File::open(path)?)?;
+    skip_to_content(&mut reader)?;
+    return hash_to_end(&mut reader);
+}
+
+/// The `(offset, length)` ranges covered by the content hash.
+pub fn content_ranges(path: &Path) -> FileResult<Vec<(u64, u64)>> {
+    if let Some(ranges) = essence_ranges(path) {
+        return Ok(ranges);
+    }
+    let mut reader = StepableBuffReader::new(File::open(path)?)?;
+    skip_to_content(&mut reader)?;
+    let start = reader.total_offset as u64;
+    let file_len = fs::metadata(path)?.len();
+    return Ok(vec![(start, file_len.saturating_sub(start))]);
+}
+
+/// Number of bytes covered by the content hash, files with different content sizes can
+/// never have the same content.
+pub fn content_size(path: &Path) -> FileResult<u64> {
+    return Ok(content_ranges(path)?.iter().map(|(_, len)| len).sum());
+}
+
+/// Hashes the first and last few KiB of the content, a cheap way to tell apart most files
+/// of the same size. Contents of up to twice that size are hashed whole.
+pub fn partial_hash(path: &Path) -> FileResult<u64> {
+    let ranges = content_ranges(path)?;
+    let content_size: u64 = ranges.iter().map(|(_, len)| len).sum();
+    let head_size = min(content_size, PARTIAL_HASH_SIZE);
+    let tail_size = min(content_size - head_size, PARTIAL_HASH_SIZE);
+    let mut span = slice_ranges(&ranges, 0, head_size);
+    span.append(&mut slice_ranges(
+        &ranges,
+        content_size - tail_size,
+        tail_size,
+    ));
+    return hash_ranges(path, &span);
+}
+
+/// Number of bytes a partial hash reads from content of `content_size` bytes.
+pub fn partial_hash_len(content_size: u64) -> u64 {
+    return min(content_size, 2 * PARTIAL_HASH_SIZE);
+}
+
+/// The part of `ranges` from `start` to `start + len`, counted in content bytes.
+fn slice_ranges(ranges: &[(u64, u64)], start: u64, len: u64) -> Vec<(u64, u64)> {
+    let mut slice = Vec::new();
+    let mut skip = start;
+    let mut remaining = len;
+    for (offset, range_len) in ranges {
+        if remaining == 0 {
+            break;
+        }
+        if skip >= *range_len {
+            skip -= range_len;
+            continue;
+        }
+        let take = min(range_len - skip, remaining);
+        slice.push((offset + skip, take));
+        remaining -= take;
+        skip = 0;
+    }
+    return slice;
+}
+
+fn hash_ranges(path: &Path, ranges: &[(u64, u64)]) -> FileResult<u64> {
+    let mut reader = ContentReader::with_ranges(path, ranges.to_vec())?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    return Ok(hasher.digest());
+}
+
+/// Reads the bytes covered by the content hash, one range after the other.
+pub struct ContentReader {
+    file: File,
+    ranges: Vec<(u64, u64)>,
+    next_range: usize,
+    remaining: u64,
+}
+
+impl ContentReader {
+    pub fn open(path: &Path) -> FileResult<Self> {
+        return ContentReader::with_ranges(path, content_ranges(path)?);
+    }
+
+    fn with_ranges(path: &Path, ranges: Vec<(u64, u64)>) -> FileResult<Self> {
+        return Ok(ContentReader {
+            file: File::open(path)?,
+            ranges,
+            next_range: 0,
+            remaining: 0,
+        });
+    }
+
+    /// Total number of content bytes.
+    pub fn len(&self) -> u64 {
+        return self.ranges.iter().map(|(_, len)| len).sum();
+    }
+}
+
+impl Read for ContentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let (offset, len) = match self.ranges.get(self.next_range) {
+                Some(range) => *range,
+                None => return Ok(0),
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = len;
+            self.next_range += 1;
+        }
+        let max = min(buf.len() as u64, self.remaining) as usize;
+        let read = self.file.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= read as u64;
+        return Ok(read);
+    }
+}
+
+fn skip_to_content(reader: &mut StepableBuffReader<File>) -> FileResult<()> {
+    let exif_tags = vec![vec![0xFF, 0xD8, 0xFF, 0xE1], vec![0xFF, 0xD8, 0xFF, 0xE0]];
+    if reader.compare_multiple_bytes(exif_tags)? {
+        while reader.increment()? {
+            if reader.compare_bytes(vec![0xFF, 0xDA])? {
+                return Ok(());
+            }
+        }
+        return Err(FileError::BadSignature("JPEG start of scan (FF DA)"));
+    }
+    return Ok(());
+}
+
+fn hash_to_end(reader: &mut StepableBuffReader<File>) -> FileResult<u64> {
+    let mut hasher = Xxh3::new();
+    reader.stream_to_end(|chunk| hasher.update(chunk))?;
+    return Ok(hasher.digest());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGES: [(u64, u64); 3] = [(10, 4), (20, 2), (30, 6)];
+
+    #[test]
+    fn file_hashes_cover_exactly_the_content_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let jpeg_header = [0xFF, 0xD8, 0xFF, 0xE1, 0, 4, 1, 2, 0xFF, 0xDA];
+        for len in [0, 10, 4095, 4096, 5000, 8193, 100_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let plain = dir.path().join(format!("{}.bin", len));
+            fs::write(&plain, &data).unwrap();
+            let jpeg = dir.path().join(format!("{}.jpg", len));
+            fs::write(&jpeg, [&jpeg_header[..], &data].concat()).unwrap();
+            for path in [&plain, &jpeg] {
+                let ranges = content_ranges(path).unwrap();
+                assert_eq!(
+                    get_file_hash(path).unwrap(),
+                    hash_ranges(path, &ranges).unwrap()
+                );
+            }
+            assert_eq!(
+                get_file_hash(&plain).unwrap(),
+                get_file_hash(&jpeg).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn slice_ranges_counts_content_bytes() {
+        assert_eq!(slice_ranges(&RANGES, 0, 3), [(10, 3)]);
+        assert_eq!(slice_ranges(&RANGES, 2, 5), [(12, 2), (20, 2), (30, 1)]);
+        assert_eq!(slice_ranges(&RANGES, 4, 2), [(20, 2)]);
+        assert_eq!(slice_ranges(&RANGES, 7, 100), [(31, 5)]);
+    }
+
+    #[test]
+    fn slice_ranges_past_the_end_is_empty() {
+        assert!(slice_ranges(&RANGES, 12, 4).is_empty());
+        assert!(slice_ranges(&RANGES, 0, 0).is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metadata_parser::datetime_parser::MetadataFormat,
+    models::{Action, ContentKey},
+    progress,
+};
+
+/// Bumped whenever the way files are hashed or their metadata is read changes, so stale
+/// entries are never reused.
+const INDEX_VERSION: u32 = 9;
+
+/// What is known about a file. For library files it is reused as long as the size and
+/// modification time of the file have not changed.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Number of bytes covered by the content hash, metadata is not counted.
+    pub content_size: u64,
+    /// Only computed when another file has the same content size.
+    pub partial_hash: Option<u64>,
+    /// Only computed when another file has the same content size and partial hash.
+    pub hash: Option<u64>,
+    /// Difference hash of the decoded image, only computed by `similar`.
+    #[serde(default)]
+    pub perceptual_hash: Option<u64>,
+    pub datetime: Option<DateTime<Utc>>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// The parser that read the metadata, `None` for documents and unrecognized media.
+    pub metadata_format: Option<MetadataFormat>,
+}
+
+impl IndexEntry {
+    /// The most precise key known for the content. Identical files always end up with the
+    /// same key, because sharing a size and partial hash gets them fully hashed.
+    pub fn content_key(&self) -> ContentKey {
+        return match (self.hash, self.partial_hash) {
+            (Some(hash), _) => ContentKey::Full(hash),
+            (None, Some(partial_hash)) => ContentKey::Partial(self.content_size, partial_hash),
+            (None, None) => ContentKey::Size(self.content_size),
+        };
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct IndexFile {
+    version: u32,
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+/// On-disk index of the library, so only new or changed files have to be hashed.
+pub struct IndexCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+impl IndexCache {
+    pub fn empty(path: &Path) -> Self {
+        return IndexCache {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+        };
+    }
+
+    /// Loads the index, starting over when it is missing, unreadable or from an older version.
+    pub fn load(path: &Path) -> Self {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return IndexCache::empty(path),
+        };
+        return match serde_json::from_reader::<_, IndexFile>(BufReader::new(file)) {
+            Ok(index) if index.version == INDEX_VERSION => IndexCache {
+                path: path.to_path_buf(),
+                entries: index.entries,
+            },
+            Ok(_) => {
+                progress::suspend(|| {
+                    println!("Index format changed, rebuilding {}", path.display())
+                });
+                IndexCache::empty(path)
+            }
+            Err(e) => {
+                progress::suspend(|| {
+                    println!("Could not read {} ({}), rebuilding it", path.display(), e)
+                });
+                IndexCache::empty(path)
+            }
+        };
+    }
+
+    /// Returns the cached entry, unless the file changed since it was indexed.
+    pub fn lookup(&self, path: &Path, size: u64, modified: SystemTime) -> Option<&IndexEntry> {
+        return self
+            .entries
+            .get(path)
+            .filter(|entry| entry.size == size && entry.modified == modified);
+    }
+
+    /// Replaces the index with the files found by the latest walk and returns the number
+    /// of stale entries that were pruned.
+    pub fn replace_entries(&mut self, entries: HashMap<PathBuf, IndexEntry>) -> usize {
+        let pruned = self
+            .entries
+            .keys()
+            .filter(|path| !entries.contains_key(*path))
+            .count();
+        self.entries = entries;
+        return pruned;
+    }
+
+    /// Updates the index with the outcome of executed actions, so moved and copied files
+    /// do not have to be indexed again on the next run. `indexed` holds the entries of
+    /// every source of the actions.
+    pub fn record_completed(
+        &mut self,
+        completed: &[(Action, PathBuf, PathBuf, Option<u64>)],
+        indexed: &HashMap<PathBuf, IndexEntry>,
+    ) {
+        for (act, src, dest, hash) in completed {
+            if *act != Action::Copy {
+                self.entries.remove(src);
+            }
+            if *act == Action::Delete {
+                continue;
+            }
+            let mut entry = match indexed.get(src) {
+                Some(entry) => entry.clone(),
+                None => continue,
+            };
+            let metadata = match fs::metadata(dest) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            entry.modified = match metadata.modified() {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+            entry.size = metadata.len();
+            entry.hash = hash.or(entry.hash);
+            self.entries.insert(dest.clone(), entry);
+        }
+    }
+
+    /// Writes the index next to its final location first, so an interrupted save never
+    /// leaves a truncated index behind.
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        let file = File::create(&tmp_path).map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+        let index = IndexFile {
+            version: INDEX_VERSION,
+            entries: self.entries.clone(),
+        };
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &index)
+            .map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+        writer
+            .flush()
+            .map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &self.path).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &Path) -> IndexEntry {
+        let metadata = fs::metadata(path).unwrap();
+        return IndexEntry {
+            size: metadata.len(),
+            modified: metadata.modified().unwrap(),
+            content_size: metadata.len(),
+            partial_hash: None,
+            hash: Some(7),
+            perceptual_hash: None,
+            datetime: None,
+            camera_make: None,
+            camera_model: None,
+            metadata_format: None,
+        };
+    }
+
+    #[test]
+    fn saved_entries_are_reused_while_the_file_is_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.jpg");
+        fs::write(&file, "a").unwrap();
+        let index_path = dir.path().join(".sorter/index.json");
+        let mut index = IndexCache::empty(&index_path);
+        index.replace_entries(HashMap::from([(file.clone(), entry(&file))]));
+        index.save().unwrap();
+
+        let index = IndexCache::load(&index_path);
+        let cached = entry(&file);
+        let hit = index.lookup(&file, cached.size, cached.modified).unwrap();
+        assert_eq!(hit.hash, Some(7));
+        assert!(index
+            .lookup(&file, cached.size + 1, cached.modified)
+            .is_none());
+        let later = cached.modified + std::time::Duration::from_secs(1);
+        assert!(index.lookup(&file, cached.size, later).is_none());
+    }
+
+    #[test]
+    fn indexes_of_another_version_or_unreadable_ones_start_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.jpg");
+        fs::write(&file, "a").unwrap();
+        let index_path = dir.path().join("index.json");
+        let cached = entry(&file);
+        let old = IndexFile {
+            version: INDEX_VERSION - 1,
+            entries: HashMap::from([(file.clone(), cached.clone())]),
+        };
+        fs::write(&index_path, serde_json::to_string(&old).unwrap()).unwrap();
+        let index = IndexCache::load(&index_path);
+        assert!(index.lookup(&file, cached.size, cached.modified).is_none());
+
+        fs::write(&index_path, "{").unwrap();
+        assert!(IndexCache::load(&index_path).entries.is_empty());
+    }
+
+    #[test]
+    fn entries_of_files_that_are_gone_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = IndexCache::empty(&dir.path().join("index.json"));
+        let files: Vec<PathBuf> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let file = dir.path().join(name);
+                fs::write(&file, name).unwrap();
+                return file;
+            })
+            .collect();
+        let entries = |files: &[PathBuf]| {
+            return files.iter().map(|f| (f.clone(), entry(f))).collect();
+        };
+        assert_eq!(index.replace_entries(entries(&files)), 0);
+        assert_eq!(index.replace_entries(entries(&files[1..2])), 2);
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), [&files[1]]);
+    }
+
+    #[test]
+    fn completed_actions_update_the_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str| {
+            let file = dir.path().join(name);
+            fs::write(&file, name).unwrap();
+            return file;
+        };
+        let (copied, moved, deleted) = (write("copied"), write("moved"), write("deleted"));
+        let indexed: HashMap<PathBuf, IndexEntry> = [&copied, &moved, &deleted]
+            .iter()
+            .map(|f| ((*f).clone(), entry(f)))
+            .collect();
+        let mut index = IndexCache::empty(&dir.path().join("index.json"));
+        index.replace_entries(indexed.clone());
+
+        let copy = write("copy");
+        fs::rename(&moved, dir.path().join("moved to")).unwrap();
+        let completed = vec![
+            (Action::Copy, copied.clone(), copy.clone(), Some(1)),
+            (
+                Action::Move,
+                moved.clone(),
+                dir.path().join("moved to"),
+                None,
+            ),
+            (Action::Delete, deleted.clone(), deleted.clone(), None),
+        ];
+        index.record_completed(&completed, &indexed);
+
+        let mut paths: Vec<&PathBuf> = index.entries.keys().collect();
+        paths.sort();
+        assert_eq!(paths, [&copied, &copy, &dir.path().join("moved to")]);
+        assert_eq!(index.entries[&copy].hash, Some(1));
+        assert_eq!(index.entries[&dir.path().join("moved to")].hash, Some(7));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::FileResult,
+    hashing::get_file_hash,
+    models::Action,
+    transfer::{move_file, Preserve},
+};
+
+/// A completed operation. The destination is the final path, after any collision suffix.
+#[derive(Deserialize, Serialize)]
+pub struct JournalEntry {
+    pub action: Action,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub hash: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Append-only log of completed operations, one JSON object per line. Every entry is
+/// written as soon as its operation finishes, so an interrupted run can still be undone.
+pub struct Journal {
+    path: PathBuf,
+    /// Created with the first entry, so runs that change nothing leave no journal behind.
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    /// Starts a new journal. An existing file is never appended to, as undoing it would then
+    /// reverse more than one run. Its folder is created right away, so a journal that cannot
+    /// be written is noticed before any file is changed.
+    pub fn create(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(format!(
+                "{}: already exists, journals are never appended to",
+                path.display()
+            ));
+        }
+        return Ok(Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(None),
+        });
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// Whether an entry was written, i.e. the journal file exists.
+    pub fn is_written(&self) -> bool {
+        return self.file.lock().is_ok_and(|file| file.is_some());
+    }
+
+    pub fn record(
+        &self,
+        action: Action,
+        source: &Path,
+        destination: &Path,
+        hash: Option<u64>,
+    ) -> FileResult<()> {
+        let entry = JournalEntry {
+            action,
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            hash: hash.map(|h| format!("{:016x}", h)).unwrap_or_default(),
+            timestamp: Utc::now(),
+        };
+        let mut line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+        line.push('\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| io::Error::other("an earlier journal write panicked"))?;
+        if file.is_none() {
+            let created = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(&self.path)?;
+            *file = Some(created);
+        }
+        if let Some(file) = file.as_mut() {
+            file.write_all(line.as_bytes())?;
+        }
+        return Ok(());
+    }
+}
+
+/// A journal name no other run gets, even one started in the same second.
+pub fn unique_journal_name() -> String {
+    return format!(
+        "{}_{}.jsonl",
+        Local::now().format("%Y%m%d_%H%M%S_%3f"),
+        std::process::id()
+    );
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        entries.push(entry);
+    }
+    return Ok(entries);
+}
+
+/// Reverses every operation in the journal, newest first: copies are removed, moved and
+/// deleted (trashed) files are put back at their source. Returns whether all of them succeeded.
+pub fn undo_journal(path: &Path) -> Result<bool, String> {
+    let entries = read_journal(path)?;
+    let mut failures = 0;
+    let mut undone = 0;
+
+    for entry in entries.iter().rev() {
+        match undo_entry(entry) {
+            Ok(true) => undone += 1,
+            Ok(false) => (),
+            Err(e) => {
+                println!("{}", e);
+                failures += 1;
+            }
+        }
+    }
+
+    println!(
+        "Undid {} of {} operations ({} failed)",
+        undone,
+        entries.len(),
+        failures
+    );
+    return Ok(failures == 0);
+}
+
+/// Returns `Ok(false)` when the operation was already undone.
+fn undo_entry(entry: &JournalEntry) -> Result<bool, String> {
+    let dest = &entry.destination;
+    if entry.action == Action::Delete && *dest == entry.source {
+        return Err(format!(
+            "{}: was permanently deleted, cannot restore",
+            dest.display()
+        ));
+    }
+    if !dest.exists() {
+        if entry.action != Action::Copy && !entry.source.exists() {
+            return Err(format!(
+                "{}: file is missing, cannot restore",
+                dest.display()
+            ));
+        }
+        return Ok(false);
+    }
+    if !entry.hash.is_empty() {
+        let hash = get_file_hash(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+        if entry.hash != format!("{:016x}", hash) {
+            return Err(format!(
+                "{}: content changed since it was written, leaving it in place",
+                dest.display()
+            ));
+        }
+    }
+    match entry.action {
+        Action::Copy => {
+            fs::remove_file(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+        }
+        Action::Move | Action::Delete => {
+            if entry.source.exists() {
+                return Err(format!(
+                    "{}: already exists, cannot restore {}",
+                    entry.source.display(),
+                    dest.display()
+                ));
+            }
+            if let Some(dir) = entry.source.parent() {
+                fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            }
+            move_file(dest, &entry.source, Preserve::ALL, None)
+                .map_err(|e| format!("{}: {}", dest.display(), e))?;
+        }
+    }
+    return Ok(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &str) -> PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        return path.to_path_buf();
+    }
+
+    fn hash(path: &Path) -> Option<u64> {
+        return Some(get_file_hash(path).unwrap());
+    }
+
+    #[test]
+    fn journals_are_created_with_the_first_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journals/run.jsonl");
+        let journal = Journal::create(&path).unwrap();
+        assert!(!path.exists() && !journal.is_written());
+
+        let (src, dest) = (dir.path().join("a"), dir.path().join("b"));
+        journal.record(Action::Move, &src, &dest, Some(1)).unwrap();
+        journal.record(Action::Delete, &dest, &dest, None).unwrap();
+        assert!(journal.is_written());
+        let entries = read_journal(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hash, "0000000000000001");
+        assert_eq!(entries[1].hash, "");
+
+        assert!(Journal::create(&path).is_err());
+    }
+
+    #[test]
+    fn failed_writes_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let journals = dir.path().join("journals");
+        let journal = Journal::create(&journals.join("run.jsonl")).unwrap();
+        fs::remove_dir(&journals).unwrap();
+        let path = dir.path().join("a");
+        assert!(journal.record(Action::Copy, &path, &path, None).is_err());
+    }
+
+    #[test]
+    fn undo_reverses_copies_moves_and_trashed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_path = dir.path().join("run.jsonl");
+        let journal = Journal::create(&journal_path).unwrap();
+        let copied = write(&dir.path().join("in/copied"), "copied");
+        let copy = write(&dir.path().join("lib/copy"), "copied");
+        journal
+            .record(Action::Copy, &copied, &copy, hash(&copy))
+            .unwrap();
+        let moved = dir.path().join("in/moved");
+        let moved_to = write(&dir.path().join("lib/moved"), "moved");
+        journal
+            .record(Action::Move, &moved, &moved_to, hash(&moved_to))
+            .unwrap();
+        let deleted = dir.path().join("lib/deleted");
+        let trashed = write(&dir.path().join("trash/deleted"), "deleted");
+        journal
+            .record(Action::Delete, &deleted, &trashed, None)
+            .unwrap();
+
+        assert!(undo_journal(&journal_path).unwrap());
+        assert!(copied.exists() && !copy.exists());
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "moved");
+        assert!(!moved_to.exists());
+        assert_eq!(fs::read_to_string(&deleted).unwrap(), "deleted");
+        // Undoing again finds nothing left to do
+        assert!(undo_journal(&journal_path).unwrap());
+    }
+
+    #[test]
+    fn undo_leaves_changed_files_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_path = dir.path().join("run.jsonl");
+        let journal = Journal::create(&journal_path).unwrap();
+        let moved = dir.path().join("in/moved");
+        let moved_to = write(&dir.path().join("lib/moved"), "moved");
+        journal
+            .record(Action::Move, &moved, &moved_to, hash(&moved_to))
+            .unwrap();
+        fs::write(&moved_to, "edited").unwrap();
+
+        assert!(!undo_journal(&journal_path).unwrap());
+        assert!(!moved.exists());
+        assert_eq!(fs::read_to_string(&moved_to).unwrap(), "edited");
+    }
+}
@@ -0,0 +1,71 @@
+use std::{env, path::PathBuf};
+
+use flexi_logger::{
+    default_format, detailed_format, Cleanup, Criterion, Duplicate, FileSpec, Logger, LoggerHandle,
+    Naming,
+};
+use indicatif_log_bridge::LogWrapper;
+use log::LevelFilter;
+
+use crate::progress;
+
+/// The log file is rotated once it reaches this size.
+const LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated log files kept next to the current one.
+const KEPT_LOG_FILES: usize = 5;
+
+/// `$XDG_STATE_HOME/rust_file_sorter/logs`, falling back to `~/.local/state`.
+pub fn default_log_dir() -> Option<PathBuf> {
+    let state_dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    return Some(state_dir.join("rust_file_sorter").join("logs"));
+}
+
+/// Logs to a rotating file in `log_dir` at debug level (trace with `-vvv`), and to the console
+/// at the level picked by `verbose`: errors only by default, then info, debug and trace.
+/// The returned handle has to be kept until the program ends.
+pub fn init(verbose: u8, log_dir: Option<PathBuf>) -> Result<LoggerHandle, String> {
+    let (max_level, console) = match verbose {
+        0 => (LevelFilter::Debug, Duplicate::Error),
+        1 => (LevelFilter::Debug, Duplicate::Info),
+        2 => (LevelFilter::Debug, Duplicate::Debug),
+        _ => (LevelFilter::Trace, Duplicate::Trace),
+    };
+    let spec = format!("warn, rust_file_sorter={}", max_level);
+    let logger = Logger::try_with_str(spec)
+        .map_err(|e| e.to_string())?
+        .format_for_files(detailed_format)
+        .format_for_stderr(default_format)
+        .duplicate_to_stderr(console);
+    let logger = match &log_dir {
+        Some(dir) => logger
+            .log_to_file(
+                FileSpec::default()
+                    .directory(dir)
+                    .basename("sorter")
+                    .suppress_timestamp(),
+            )
+            .append()
+            .rotate(
+                Criterion::Size(LOG_FILE_SIZE),
+                Naming::Numbers,
+                Cleanup::KeepLogFiles(KEPT_LOG_FILES),
+            ),
+        None => logger.do_not_log(),
+    };
+    let (log, handle) = logger.build().map_err(|e| match &log_dir {
+        Some(dir) => format!("{}: {}", dir.display(), e),
+        None => e.to_string(),
+    })?;
+    LogWrapper::new(progress::multi().clone(), log)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    // The bridge derives the maximum from the default level, which would hide this crate's
+    // debug messages
+    log::set_max_level(max_level);
+    return Ok(handle);
+}
@@ -1,51 +1,129 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+mod cli;
+mod config;
+mod duplicates;
+mod error;
+mod hashing;
+mod index_cache;
+mod journal;
+mod logging;
 mod metadata_parser;
 mod models;
+mod plan;
+mod progress;
+mod report;
+mod similar;
 mod sorting;
+mod template;
+mod transfer;
+mod trash;
 mod util;
 
-use models::{Config, FileTypes};
-use std::{collections::HashMap, str, time::Instant};
+use clap::Parser;
+use cli::{Cli, Command};
+use std::{process::ExitCode, time::Instant};
+use transfer::{Preserve, TransferOptions};
 
-fn main() {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
     let now = Instant::now();
-
-    let file_extensions: HashMap<&str, &FileTypes> = [
-        ("doc", FileTypes::DOCUMENT),
-        ("docx", FileTypes::DOCUMENT),
-        ("pdf", FileTypes::DOCUMENT),
-        ("ppt", FileTypes::DOCUMENT),
-        ("pptx", FileTypes::DOCUMENT),
-        ("xls", FileTypes::DOCUMENT),
-        ("xlsx", FileTypes::DOCUMENT),
-        ("jpeg", FileTypes::IMAGE),
-        ("jpg", FileTypes::IMAGE),
-        ("png", FileTypes::IMAGE),
-        ("avi", FileTypes::VIDEO),
-        ("mov", FileTypes::VIDEO),
-        ("mp4", FileTypes::VIDEO),
-    ]
-    .iter()
-    .map(|(e, f)| return (e.clone(), f.clone()))
-    .collect();
-
-    let config = Config {
-        // source_dir: "/mnt/c/source/LOUISE/VIDEO SPEEL KLAVIER".to_owned(),
-        source_dir: "/mnt/c/source/my pictures".to_owned(),
-        doc_dir: "/mnt/c/dest/doc".to_owned(),
-        image_dir: "/mnt/c/dest/image".to_owned(),
-        video_dir: "/mnt/c/dest/video".to_owned(),
-        file_exts: file_extensions,
+    let log_dir = cli.log_dir.clone().or_else(logging::default_log_dir);
+    let _logger = match logging::init(cli.verbose, log_dir) {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            eprintln!("Warning: could not open the log file: {}", e);
+            logging::init(cli.verbose, None).ok()
+        }
     };
-    config.create_folders();
 
-    // what happens if two files are identified. both hashes are the same, but one has exif data
-    // if dest folder has files, move files to interim place
+    return match run(cli) {
+        Ok(success) => {
+            eprintln!("Seconds elapased: {}s", now.elapsed().as_secs_f32());
+            match success {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(2)
+        }
+    };
+}
 
-    sorting::sort_files(config);
-    // let dt = metadata_parser::datetime_parser::read_datetime(&std::path::PathBuf::from(
-    //     "/mnt/c/source/Whatsapp Images/IMG-20141009-WA0000.jpg",
-    // ));
-    // println!("{}", dt.unwrap().to_rfc2822());
+/// Runs the selected command, returning whether it succeeded or an error for invalid input.
+fn run(cli: Cli) -> Result<bool, String> {
+    let profile = cli.load_profile()?;
 
-    println!("Seconds elapased: {}s", now.elapsed().as_secs_f32());
+    return match cli.command {
+        Command::Sort {
+            source,
+            library,
+            mode,
+            run,
+        } => {
+            let mut config = library.to_config(&profile, source.resolve(&profile)?, mode)?;
+            run.apply_to(&mut config);
+            if !config.only_plans() {
+                config.create_folders();
+            }
+            Ok(sorting::sort_files(config))
+        }
+        Command::Index { dirs, exts } => Ok(sorting::print_index(dirs, &exts.file_exts(&profile))),
+        Command::Dedupe { library, run } => {
+            let mut config = library.to_config(&profile, Vec::new(), None)?;
+            run.apply_to(&mut config);
+            if !config.only_plans() {
+                config.create_folders();
+            }
+            Ok(sorting::dedupe_files(config))
+        }
+        Command::Apply {
+            plan,
+            journal,
+            library_root,
+            permanent_delete,
+            verify_copies,
+            preserve,
+            mtime_from_metadata,
+        } => sorting::apply_plan(
+            &plan,
+            journal,
+            library_root,
+            permanent_delete,
+            TransferOptions {
+                verify_copies,
+                preserve: match preserve.is_empty() {
+                    true => Preserve::ALL.to_vec(),
+                    false => preserve,
+                },
+                mtime_from_metadata,
+            },
+        ),
+        Command::PurgeTrash {
+            library,
+            older_than_days,
+            all,
+        } => {
+            let config = library.to_config(&profile, Vec::new(), None)?;
+            let retention_days = older_than_days.unwrap_or(config.trash_retention_days);
+            trash::purge_trash(&config.get_library_root(), retention_days, all)
+        }
+        Command::Undo { journal } => journal::undo_journal(&journal),
+        Command::Inspect { files, exts } => {
+            Ok(sorting::inspect_files(&files, &exts.file_exts(&profile)))
+        }
+        Command::Verify { source, library } => {
+            let config = library.to_config(&profile, source.resolve(&profile)?, None)?;
+            Ok(sorting::verify_files(config))
+        }
+        Command::Similar {
+            library,
+            max_distance,
+        } => {
+            let config = library.to_config(&profile, Vec::new(), None)?;
+            Ok(similar::find_similar(config, max_distance))
+        }
+    };
 }
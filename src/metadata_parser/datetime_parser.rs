@@ -1,11 +1,13 @@
-use std::panic;
-use std::{fs::File, path::PathBuf};
+use std::{cmp::min, fs::File, path::Path, time::SystemTime};
 
+use crate::error::{FileError, FileResult};
 use crate::metadata_parser::exif_parser;
 use crate::metadata_parser::quicktime_parser;
 use crate::metadata_parser::riff_parser;
 use crate::util::io::StepableBuffReader;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
 
 // const MEDIA_TAG: &[u8] = "mdhd".as_bytes();
 // const EXIF_TAG: &[u8] = "Exif".as_bytes();
@@ -81,43 +83,130 @@ use chrono::{DateTime, Utc};
 //     }
 // }
 
-pub(crate) fn read_datetime(path: &PathBuf) -> Option<DateTime<Utc>> {
-    let test = panic::catch_unwind(|| {
-        let mut reader = StepableBuffReader::new(File::open(path).unwrap());
-        // if file starts with FF D8 FF E1 or FF D8 FF E0 -> read exif
-        // if file starts with 49 49 2A 00 or 4D 4D 00 2A -> read tiff
-        // if file is video, check for mdhd
-        if reader.peak(4).eq(&exif_parser::EXIF_SIGN_1)
-            || reader.peak(4).eq(&exif_parser::EXIF_SIGN_2)
-        {
-            // println!("EXIF");
-            return exif_parser::parse_datetime(&mut reader);
-        }
-        if reader.compare_bytes(riff_parser::RIFF_SIGN.to_vec()) {
-            // println!("RIFF");
-            return riff_parser::parse_datetime(&mut reader);
-        }
-        if reader
-            .peak(8)
-            .ends_with(&quicktime_parser::QUICKTIME_SIGN_1)
-            || reader
-                .peak(8)
-                .ends_with(&quicktime_parser::QUICKTIME_SIGN_2)
-        {
-            // println!("Quicktime");
-            return quicktime_parser::parse_datetime(path);
-        }
-        None
-    });
-    match test {
-        Ok(dt) => return Some(dt?),
-        Err(_) => {
-            println!("Oh fuck {}", path.display());
-            return None;
+/// The metadata standards a datetime is read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum MetadataFormat {
+    Exif,
+    Riff,
+    QuickTime,
+}
+
+impl MetadataFormat {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            MetadataFormat::Exif => "EXIF",
+            MetadataFormat::Riff => "RIFF",
+            MetadataFormat::QuickTime => "QuickTime",
+        };
+    }
+}
+
+/// What is read from a file's metadata. Fields the format does not have are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct MediaMetadata {
+    /// The time shown on the camera's clock, stored as if it were UTC.
+    pub datetime: Option<DateTime<Utc>>,
+    /// How far the camera's clock was ahead of UTC, `None` when the metadata does not say.
+    pub utc_offset: Option<FixedOffset>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+/// Reads the creation datetime from the file's metadata. Returns `Ok(None)` for formats
+/// without a known datetime and files that have none.
+pub(crate) fn read_datetime(path: &Path) -> FileResult<Option<DateTime<Utc>>> {
+    return read_metadata(path).1.map(|metadata| metadata.datetime);
+}
+
+/// The moment the file was created. The datetime is taken to be in the time zone recorded
+/// with it, or else in the local time zone.
+pub(crate) fn read_capture_time(path: &Path) -> FileResult<Option<SystemTime>> {
+    let metadata = read_metadata(path).1?;
+    let naive = match metadata.datetime {
+        Some(datetime) => datetime.naive_utc(),
+        None => return Ok(None),
+    };
+    let datetime = match metadata.utc_offset {
+        Some(offset) => offset
+            .from_local_datetime(&naive)
+            .single()
+            .map(|dt| dt.to_utc()),
+        // A time skipped by a daylight saving change has no local time, a repeated one two
+        None => Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|dt| dt.to_utc()),
+    };
+    return Ok(datetime.map(SystemTime::from));
+}
+
+/// Reads the datetime and camera, also returning the format whose parser the file was
+/// handed to.
+pub(crate) fn read_metadata(path: &Path) -> (Option<MetadataFormat>, FileResult<MediaMetadata>) {
+    let mut reader = match File::open(path)
+        .map_err(FileError::from)
+        .and_then(StepableBuffReader::new)
+    {
+        Ok(reader) => reader,
+        Err(e) => return (None, Err(e)),
+    };
+    let format = match detect_format(&mut reader) {
+        Ok(format) => format,
+        Err(e) => return (None, Err(e)),
+    };
+    let metadata = match format {
+        Some(MetadataFormat::Exif) => exif_parser::parse_metadata(&mut reader),
+        Some(MetadataFormat::Riff) => {
+            riff_parser::parse_datetime(&mut reader).map(|dt| from_datetime(dt, None))
         }
+        // QuickTime times are UTC
+        Some(MetadataFormat::QuickTime) => quicktime_parser::parse_datetime(path)
+            .map(|dt| from_datetime(dt, FixedOffset::east_opt(0))),
+        None => Ok(MediaMetadata::default()),
+    };
+    let parser = format.map_or("no", |f| f.name());
+    match &metadata {
+        Ok(MediaMetadata {
+            datetime: Some(dt), ..
+        }) => debug!("{}: {} metadata, datetime {}", path.display(), parser, dt),
+        Ok(_) => debug!("{}: {} metadata, no datetime", path.display(), parser),
+        Err(e) => debug!("{}: {} metadata, {}", path.display(), parser, e),
     }
+    return (format, metadata);
+}
 
-    return None;
+fn from_datetime(
+    datetime: Option<DateTime<Utc>>,
+    utc_offset: Option<FixedOffset>,
+) -> MediaMetadata {
+    return MediaMetadata {
+        datetime,
+        utc_offset,
+        ..Default::default()
+    };
+}
+
+/// Picks the parser from the first bytes. A RIFF signature is consumed, the RIFF parser
+/// continues right after it.
+fn detect_format(reader: &mut StepableBuffReader<File>) -> FileResult<Option<MetadataFormat>> {
+    let header = reader.peak(min(8, reader.available()))?;
+    // if file starts with FF D8 FF E1 or FF D8 FF E0 -> read exif
+    // if file starts with 49 49 2A 00 or 4D 4D 00 2A -> read tiff
+    // if file is video, check for mdhd
+    if header.starts_with(exif_parser::EXIF_SIGN_1) || header.starts_with(exif_parser::EXIF_SIGN_2)
+    {
+        return Ok(Some(MetadataFormat::Exif));
+    }
+    if reader.compare_bytes(riff_parser::RIFF_SIGN.to_vec())? {
+        return Ok(Some(MetadataFormat::Riff));
+    }
+    if header.len() == 8
+        && (header.ends_with(&quicktime_parser::QUICKTIME_SIGN_1)
+            || header.ends_with(&quicktime_parser::QUICKTIME_SIGN_2))
+    {
+        return Ok(Some(MetadataFormat::QuickTime));
+    }
+    return Ok(None);
 }
 
 // fn read_file_chunk(path: &str, start: bool) -> Result<[u8; CHUNK_SIZE], Error> {
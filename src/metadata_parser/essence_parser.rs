@@ -0,0 +1,331 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::validate;
+
+const PNG_SIGN: &[u8] = &[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+const PNG_IMAGE_DATA: &[u8] = b"IDAT";
+/// Critical chunks besides the image data: the dimensions, bit depth and colour type, and the
+/// palette the image data indexes into.
+const PNG_DECODING: [&[u8]; 2] = [b"IHDR", b"PLTE"];
+const PNG_END: &[u8] = b"IEND";
+/// Top-level boxes a QuickTime or ISO media file (MP4, MOV, HEIC) can start with.
+const ISO_MEDIA_SIGNS: [&[u8]; 4] = [b"ftyp", b"moov", b"wide", b"mdat"];
+const ISO_MEDIA_DATA: &[u8] = b"mdat";
+const ISO_MEDIA_FILE_TYPE: &[u8] = b"ftyp";
+/// Brands of HEIF images (HEIC, AVIF), which keep their EXIF and XMP items in `mdat` too.
+const HEIF_BRANDS: [&[u8]; 11] = [
+    b"mif1", b"mif2", b"msf1", b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"avif",
+    b"avis",
+];
+/// Longest `ftyp` box whose brands are read, real ones list a handful.
+const MAX_FILE_TYPE_LEN: u64 = 4096;
+const TIFF_SIGN_LE: &[u8] = &[0x49, 0x49, 0x2A, 0x00]; // II*
+const TIFF_SIGN_BE: &[u8] = &[0x4D, 0x4D, 0x00, 0x2A]; // MM*
+const TIFF_STRIP_OFFSETS: u16 = 273;
+const TIFF_STRIP_BYTE_COUNTS: u16 = 279;
+const TIFF_TILE_OFFSETS: u16 = 324;
+const TIFF_TILE_BYTE_COUNTS: u16 = 325;
+const TIFF_SUB_IFDS: u16 = 330;
+/// Tags that change how the strips decode: width, length, bits per sample, compression,
+/// photometric interpretation, samples per pixel and planar configuration.
+const TIFF_DECODING: [u16; 7] = [256, 257, 258, 259, 262, 277, 284];
+const TIFF_MAX_IFDS: usize = 64;
+
+/// The `(offset, length)` ranges holding the image or media data of a PNG, QuickTime/ISO
+/// media or TIFF (including DNG) file. Metadata stored outside of them can change without
+/// changing the ranges' content. Returns `None` for other formats and malformed files.
+pub fn essence_ranges(path: &Path) -> Option<Vec<(u64, u64)>> {
+    let mut file = File::open(path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+    let header = read_at(&mut file, 0, file_len.min(8))?;
+
+    let ranges = if header.starts_with(PNG_SIGN) {
+        png_ranges(&mut file, file_len)?
+    } else if header.len() == 8 && ISO_MEDIA_SIGNS.contains(&&header[4..8]) {
+        iso_media_ranges(&mut file, file_len)?
+    } else if header.starts_with(TIFF_SIGN_LE) || header.starts_with(TIFF_SIGN_BE) {
+        tiff_ranges(&mut file, file_len, header.starts_with(TIFF_SIGN_BE))?
+    } else {
+        return None;
+    };
+    validate!(!ranges.is_empty())?;
+    validate!(ranges
+        .iter()
+        .all(|(offset, len)| offset.checked_add(*len).is_some_and(|end| end <= file_len)))?;
+    return Some(ranges);
+}
+
+/// The type and data of the IHDR and PLTE chunks and the data of every IDAT chunk, ancillary
+/// chunks (text, EXIF, ...) are skipped.
+fn png_ranges(file: &mut File, file_len: u64) -> Option<Vec<(u64, u64)>> {
+    let mut ranges = Vec::new();
+    let mut offset = PNG_SIGN.len() as u64;
+    while offset + 12 <= file_len {
+        let header = read_at(file, offset, 8)?;
+        let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+        let chunk_type = &header[4..8];
+        if chunk_type == PNG_IMAGE_DATA {
+            ranges.push((offset + 8, len));
+        } else if PNG_DECODING.contains(&chunk_type) {
+            ranges.push((offset + 4, 4 + len));
+        }
+        if chunk_type == PNG_END {
+            break;
+        }
+        offset += 12 + len; // length, type and CRC
+    }
+    return Some(ranges);
+}
+
+/// The payload of every top-level mdat box, the moov box with its metadata is skipped. HEIF
+/// images are left to the full hash, as their metadata items are stored in `mdat`.
+fn iso_media_ranges(file: &mut File, file_len: u64) -> Option<Vec<(u64, u64)>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= file_len {
+        let header = read_at(file, offset, (file_len - offset).min(16))?;
+        let (header_len, box_len) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => (8, file_len - offset),
+            1 if header.len() == 16 => (16, u64::from_be_bytes(header[8..16].try_into().unwrap())),
+            1 => return None,
+            len => (8, len as u64),
+        };
+        validate!(box_len >= header_len)?;
+        if &header[4..8] == ISO_MEDIA_FILE_TYPE {
+            validate!(box_len <= MAX_FILE_TYPE_LEN)?;
+            let brands = read_at(file, offset + header_len, box_len - header_len)?;
+            // The major brand, the minor version and the compatible brands
+            validate!(!brands
+                .chunks_exact(4)
+                .enumerate()
+                .any(|(i, brand)| i != 1 && HEIF_BRANDS.contains(&brand)))?;
+        }
+        if &header[4..8] == ISO_MEDIA_DATA {
+            ranges.push((offset + header_len, box_len - header_len));
+        }
+        offset = offset.checked_add(box_len)?;
+    }
+    return Some(ranges);
+}
+
+/// The image strips or tiles of every IFD and sub-IFD (DNG keeps the raw image in a
+/// sub-IFD), preceded by the entries of the tags that change how they decode. The EXIF IFD
+/// and JPEG thumbnails are not strips and are skipped.
+fn tiff_ranges(file: &mut File, file_len: u64, big_endian: bool) -> Option<Vec<(u64, u64)>> {
+    let mut tiff = TiffReader {
+        file,
+        file_len,
+        big_endian,
+    };
+    let mut ifds = vec![tiff.u32_at(4)? as u64];
+    let mut visited = HashSet::new();
+    let mut decoding = Vec::new();
+    let mut ranges = Vec::new();
+    while let Some(ifd) = ifds.pop() {
+        if ifd == 0 || !visited.insert(ifd) {
+            continue;
+        }
+        validate!(visited.len() <= TIFF_MAX_IFDS)?;
+        let num_entries = tiff.u16_at(ifd)? as u64;
+        let mut offsets = Vec::new();
+        let mut byte_counts = Vec::new();
+        for i in 0..num_entries {
+            let entry = ifd + 2 + i * 12;
+            match tiff.u16_at(entry)? {
+                TIFF_STRIP_OFFSETS | TIFF_TILE_OFFSETS => offsets = tiff.values(entry)?,
+                TIFF_STRIP_BYTE_COUNTS | TIFF_TILE_BYTE_COUNTS => {
+                    byte_counts = tiff.values(entry)?
+                }
+                TIFF_SUB_IFDS => ifds.extend(tiff.values(entry)?),
+                tag if TIFF_DECODING.contains(&tag) => {
+                    // The tag, type and count, then the values wherever they are stored
+                    decoding.push((entry, 8));
+                    decoding.push(tiff.value_range(entry)?);
+                }
+                _ => (),
+            }
+        }
+        validate!(offsets.len() == byte_counts.len())?;
+        ranges.extend(offsets.into_iter().zip(byte_counts));
+        ifds.push(tiff.u32_at(ifd + 2 + num_entries * 12)? as u64);
+    }
+    // Strips in file order, the tags in the order they were found, as rewriting the
+    // metadata can move the IFDs
+    ranges.sort();
+    decoding.append(&mut ranges);
+    return Some(decoding);
+}
+
+struct TiffReader<'a> {
+    file: &'a mut File,
+    file_len: u64,
+    big_endian: bool,
+}
+
+impl TiffReader<'_> {
+    fn u16_at(&mut self, offset: u64) -> Option<u16> {
+        let bytes = read_at(self.file, offset, 2)?;
+        return Some(self.decode(&bytes) as u16);
+    }
+
+    fn u32_at(&mut self, offset: u64) -> Option<u32> {
+        let bytes = read_at(self.file, offset, 4)?;
+        return Some(self.decode(&bytes) as u32);
+    }
+
+    /// The SHORT, LONG or IFD values of an entry.
+    fn values(&mut self, entry: u64) -> Option<Vec<u64>> {
+        let value_size = self.value_size(entry)?;
+        let (offset, len) = self.value_range(entry)?;
+        let bytes = read_at(self.file, offset, len)?;
+        return Some(
+            bytes
+                .chunks_exact(value_size as usize)
+                .map(|value| self.decode(value))
+                .collect(),
+        );
+    }
+
+    /// Where the values of a SHORT, LONG or IFD entry are, in the entry itself when they fit.
+    fn value_range(&mut self, entry: u64) -> Option<(u64, u64)> {
+        let value_size = self.value_size(entry)?;
+        let len = (self.u32_at(entry + 4)? as u64).checked_mul(value_size)?;
+        let mut offset = entry + 8;
+        if len > 4 {
+            offset = self.u32_at(offset)? as u64;
+        }
+        validate!(offset + len <= self.file_len)?;
+        return Some((offset, len));
+    }
+
+    fn value_size(&mut self, entry: u64) -> Option<u64> {
+        return match self.u16_at(entry + 2)? {
+            3 => Some(2),
+            4 | 13 => Some(4),
+            _ => None,
+        };
+    }
+
+    fn decode(&self, bytes: &[u8]) -> u64 {
+        let ordered: Vec<u8> = match self.big_endian {
+            true => bytes.to_vec(),
+            false => bytes.iter().rev().cloned().collect(),
+        };
+        return ordered
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64);
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut buffer = vec![0; len as usize];
+    file.read_exact(&mut buffer).ok()?;
+    return Some(buffer);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn temp_file(content: &[u8]) -> (tempfile::NamedTempFile, File, u64) {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(content).unwrap();
+        let file = temp.reopen().unwrap();
+        return (temp, file, content.len() as u64);
+    }
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]); // CRC
+        return chunk;
+    }
+
+    fn iso_box(box_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut iso_box = (8 + data.len() as u32).to_be_bytes().to_vec();
+        iso_box.extend_from_slice(box_type);
+        iso_box.extend_from_slice(data);
+        return iso_box;
+    }
+
+    #[test]
+    fn png_ranges_skip_ancillary_chunks() {
+        let mut png = PNG_SIGN.to_vec();
+        png.extend(png_chunk(b"IHDR", &[1; 13]));
+        png.extend(png_chunk(b"tEXt", b"hello"));
+        png.extend(png_chunk(b"PLTE", &[2; 6]));
+        png.extend(png_chunk(b"IDAT", &[3; 3]));
+        png.extend(png_chunk(b"IDAT", &[4; 2]));
+        png.extend(png_chunk(b"IEND", &[]));
+        let (_temp, mut file, len) = temp_file(&png);
+        assert_eq!(
+            png_ranges(&mut file, len).unwrap(),
+            [(12, 17), (54, 10), (76, 3), (91, 2)]
+        );
+    }
+
+    #[test]
+    fn iso_media_ranges_take_the_mdat_payloads() {
+        let mut mp4 = iso_box(b"ftyp", b"isom0000");
+        mp4.extend(iso_box(b"moov", &[1; 8]));
+        // 64-bit size
+        mp4.extend(1u32.to_be_bytes());
+        mp4.extend(b"mdat");
+        mp4.extend(20u64.to_be_bytes());
+        mp4.extend([2; 4]);
+        // Size 0, up to the end of the file
+        mp4.extend(0u32.to_be_bytes());
+        mp4.extend(b"mdat");
+        mp4.extend([3; 5]);
+        let (_temp, mut file, len) = temp_file(&mp4);
+        assert_eq!(
+            iso_media_ranges(&mut file, len).unwrap(),
+            [(48, 4), (60, 5)]
+        );
+    }
+
+    #[test]
+    fn heif_images_are_left_to_the_full_hash() {
+        for brands in [&b"heic0000mif1"[..], b"isom0000mif1heic", b"avif0000"] {
+            let mut heic = iso_box(b"ftyp", brands);
+            heic.extend(iso_box(b"meta", &[1; 8]));
+            heic.extend(iso_box(b"mdat", b"Exif data"));
+            let (_temp, mut file, len) = temp_file(&heic);
+            assert!(iso_media_ranges(&mut file, len).is_none());
+        }
+    }
+
+    #[test]
+    fn iso_media_ranges_reject_boxes_shorter_than_their_header() {
+        let mut mp4 = iso_box(b"ftyp", b"isom0000");
+        mp4.extend(4u32.to_be_bytes());
+        mp4.extend(b"mdat");
+        let (_temp, mut file, len) = temp_file(&mp4);
+        assert!(iso_media_ranges(&mut file, len).is_none());
+    }
+
+    #[test]
+    fn essence_ranges_are_checked_against_the_file() {
+        let mut mp4 = iso_box(b"ftyp", b"isom0000");
+        mp4.extend(iso_box(b"mdat", &[1; 4]));
+        let (temp, _, _) = temp_file(&mp4);
+        assert_eq!(essence_ranges(temp.path()), Some(vec![(24, 4)]));
+
+        mp4.extend(100u32.to_be_bytes());
+        mp4.extend(b"mdat");
+        let (temp, _, _) = temp_file(&mp4);
+        assert_eq!(essence_ranges(temp.path()), None);
+
+        let (temp, _, _) = temp_file(b"plain text");
+        assert_eq!(essence_ranges(temp.path()), None);
+    }
+}
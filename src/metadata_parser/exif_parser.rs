@@ -1,10 +1,12 @@
 use std::io::{Read, Seek};
 
-use chrono::{DateTime, Utc};
+use chrono::{FixedOffset, Timelike};
+use log::trace;
 
+use crate::error::{FileError, FileResult};
+use crate::metadata_parser::datetime_parser::MediaMetadata;
 use crate::util;
 use crate::util::io::StepableBuffReader;
-use crate::validate;
 
 pub const EXIF_SIGN_1: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0];
 pub const EXIF_SIGN_2: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE1];
@@ -16,65 +18,164 @@ const HEADER: &[u8] = &[00, 0x2a];
 const IFD_OFFSET: &[u8] = &[00, 00, 00, 0x08];
 const IFD_POINTER: &[u8] = &[0x87, 0x69];
 const DATE_TIME_TAG: &[u8] = &[0x90, 0x03];
+const MAKE_TAG: &[u8] = &[0x01, 0x0F];
+const MODEL_TAG: &[u8] = &[0x01, 0x10];
+const OFFSET_TAG: u16 = 0x9011; // OffsetTimeOriginal
+const SUB_SEC_TAG: u16 = 0x9291; // SubSecTimeOriginal
 const END_TAG: &[u8] = &[00, 00, 00, 00];
 
-pub fn parse_datetime<R: Read + Seek>(reader: &mut StepableBuffReader<R>) -> Option<DateTime<Utc>> {
-    reader.increment_by(4); // signature
-    if reader.compare_bytes(JFIF_TAG.to_vec()) {
-        reader.increment_by(12);
+/// Reads the datetime and the camera make and model. Make and model are in the first IFD,
+/// ahead of the pointer to the IFD holding the datetime.
+pub fn parse_metadata<R: Read + Seek>(
+    reader: &mut StepableBuffReader<R>,
+) -> FileResult<MediaMetadata> {
+    let mut metadata = MediaMetadata::default();
+    reader.increment_by(4)?; // signature
+    if reader.compare_bytes(JFIF_TAG.to_vec())? {
+        reader.increment_by(12)?;
     } else {
-        reader.increment_by(2);
+        reader.increment_by(2)?;
     }
-    // println!("{:?}", reader.peak(16));
-    validate!(reader.compare_bytes(EXIF_TAG.to_vec()))?;
-    // println!("test");
-    reader.increment_by(2);
+    if !reader.compare_bytes(EXIF_TAG.to_vec())? {
+        return Ok(metadata);
+    }
+    reader.increment_by(2)?;
 
-    let big_endian = match reader.read(2).as_slice() {
+    let big_endian = match reader.read(2)?.as_slice() {
         BIG_ENDIAN_TAG => true,
         LITTLE_ENDIAN_TAG => false,
-        _ => return None,
+        _ => return Err(FileError::BadSignature("EXIF byte order (II or MM)")),
     };
 
     let start_offset = reader.total_offset;
-    validate!(reader.compare_endian_bytes(HEADER.to_vec(), big_endian))?;
-    validate!(reader.compare_endian_bytes(IFD_OFFSET.to_vec(), big_endian))?;
-    // println!("value: {}", big_endian);
-    reader.increment_by(2); // interop
+    if !reader.compare_endian_bytes(HEADER.to_vec(), big_endian)?
+        || !reader.compare_endian_bytes(IFD_OFFSET.to_vec(), big_endian)?
+    {
+        return Err(FileError::BadSignature("EXIF TIFF header"));
+    }
+    trace!("EXIF byte order: {}", if big_endian { "MM" } else { "II" });
+    reader.increment_by(2)?; // interop
 
     loop {
-        if reader.compare_endian_bytes(DATE_TIME_TAG.to_vec(), big_endian) {
-            reader.increment_by(2); // Type
-            let length = reader.read_u32(big_endian);
-            let mut offset = reader.read_u32(big_endian) as usize;
+        if reader.compare_endian_bytes(DATE_TIME_TAG.to_vec(), big_endian)? {
+            reader.increment_by(2)?; // Type
+            let length = reader.read_u32(big_endian)?;
+            let mut offset = reader.read_u32(big_endian)? as usize;
             if offset == 0 {
-                return None;
+                return Ok(metadata);
             }
             offset += start_offset - 2;
-            // println!("Datetime tag length {} and offset {}", length, offset);
-            let buffer = reader.read_from(offset, length as usize);
-            // println!("Datetime bytes {:?}", buffer);
-            let mut datetime = std::str::from_utf8(buffer.as_slice()).unwrap().trim();
+            trace!("EXIF datetime tag: length {}, offset {}", length, offset);
+            let buffer = reader.read_at(offset, length as usize)?;
+            let mut datetime = std::str::from_utf8(buffer.as_slice())
+                .map_err(|_| FileError::Unsupported("EXIF datetime is not text".to_owned()))?
+                .trim();
             datetime =
                 datetime.trim_matches(&[char::from(0), char::from(10), char::from(13)] as &[_]);
-            // println!("{}", datetime);
-            return Some(util::parse_datetime(datetime)?);
+            trace!("EXIF datetime: {}", datetime);
+            metadata.datetime = util::parse_datetime(datetime);
+            if let Some(dt) = metadata.datetime {
+                let (offset, nanos) = read_time_details(reader, start_offset - 2, big_endian)?;
+                metadata.utc_offset = offset;
+                metadata.datetime = nanos
+                    .and_then(|nanos| dt.with_nanosecond(nanos))
+                    .or(Some(dt));
+            }
+            return Ok(metadata);
+        }
+        if reader.compare_endian_bytes(END_TAG.to_vec(), big_endian)? {
+            return Ok(metadata);
+        }
+        if reader.compare_endian_bytes(MAKE_TAG.to_vec(), big_endian)? {
+            metadata.camera_make = read_text(reader, start_offset - 2, big_endian)?;
+            trace!("EXIF make: {:?}", metadata.camera_make);
+            continue;
         }
-        if reader.compare_endian_bytes(END_TAG.to_vec(), big_endian) {
-            return None;
+        if reader.compare_endian_bytes(MODEL_TAG.to_vec(), big_endian)? {
+            metadata.camera_model = read_text(reader, start_offset - 2, big_endian)?;
+            trace!("EXIF model: {:?}", metadata.camera_model);
+            continue;
         }
-        if reader.compare_endian_bytes(IFD_POINTER.to_vec(), big_endian) {
-            reader.increment_by(2); // Type
-            reader.increment_by(4); // Count
-            let offset = reader.read_u32(big_endian) as usize;
-            // println!("Incrementing by {}", offset);
-            reader.increment_by((start_offset + offset) - reader.total_offset);
+        if reader.compare_endian_bytes(IFD_POINTER.to_vec(), big_endian)? {
+            reader.increment_by(2)?; // Type
+            reader.increment_by(4)?; // Count
+            let offset = reader.read_u32(big_endian)? as usize;
+            trace!("Following EXIF IFD pointer to offset {}", offset);
+            let skip = (start_offset + offset)
+                .checked_sub(reader.total_offset)
+                .ok_or_else(|| FileError::Unsupported("EXIF IFD points backwards".to_owned()))?;
+            reader.increment_by(skip)?;
             continue;
         }
-        if !reader.increment_by(12) {
+        if !reader.increment_by(12)? {
             break;
         }
     }
 
-    return None;
+    return Ok(metadata);
+}
+
+/// Looks for the time zone offset and the sub-seconds of the datetime among the entries
+/// following it. Tags are sorted, so the search ends at the first tag past the sub-seconds.
+fn read_time_details<R: Read + Seek>(
+    reader: &mut StepableBuffReader<R>,
+    tiff_start: usize,
+    big_endian: bool,
+) -> FileResult<(Option<FixedOffset>, Option<u32>)> {
+    let mut offset = None;
+    while reader.available() >= 12 {
+        let tag: [u8; 2] = reader.peak(2)?.try_into().unwrap();
+        let tag = match big_endian {
+            true => u16::from_be_bytes(tag),
+            false => u16::from_le_bytes(tag),
+        };
+        if tag == 0 || tag > SUB_SEC_TAG {
+            break;
+        }
+        if tag == OFFSET_TAG {
+            reader.increment_by(2)?;
+            // Such as `+02:00`, cameras that do not know their time zone leave it blank
+            let text = read_text(reader, tiff_start, big_endian)?;
+            trace!("EXIF time zone offset: {:?}", text);
+            offset = text.and_then(|text| text.parse().ok());
+            continue;
+        }
+        if tag == SUB_SEC_TAG {
+            reader.increment_by(2)?;
+            let digits = match read_text(reader, tiff_start, big_endian)? {
+                Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => digits,
+                _ => return Ok((offset, None)),
+            };
+            trace!("EXIF sub-seconds: {}", digits);
+            // The digits are a decimal fraction, `5` being half a second
+            let nanos = format!("{:0<9.9}", digits);
+            return Ok((offset, nanos.parse().ok()));
+        }
+        reader.increment_by(12)?;
+    }
+    return Ok((offset, None));
+}
+
+/// Reads the ASCII value of the entry whose tag was just passed, leaving the reader at the
+/// next entry. Values of up to 4 bytes are stored in place of the offset.
+fn read_text<R: Read + Seek>(
+    reader: &mut StepableBuffReader<R>,
+    tiff_start: usize,
+    big_endian: bool,
+) -> FileResult<Option<String>> {
+    reader.increment_by(2)?; // Type
+    let length = reader.read_u32(big_endian)? as usize;
+    let buffer = match length {
+        0..=4 => reader.read(4)?[..length].to_vec(),
+        _ => {
+            let offset = reader.read_u32(big_endian)? as usize;
+            reader.read_at(tiff_start + offset, length)?
+        }
+    };
+    let text = String::from_utf8_lossy(&buffer);
+    let text = text.trim_matches(|c: char| c == char::from(0) || c.is_whitespace());
+    return Ok(match text.is_empty() {
+        true => None,
+        false => Some(text.to_owned()),
+    });
 }
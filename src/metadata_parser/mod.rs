@@ -1,4 +1,5 @@
 pub mod datetime_parser;
+pub mod essence_parser;
 mod exif_parser;
 mod quicktime_parser;
 mod riff_parser;
@@ -1,6 +1,9 @@
-use std::{fs::File, os::unix::prelude::FileExt, path::PathBuf};
+use std::{fs::File, os::unix::prelude::FileExt, path::Path};
 
 use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::trace;
+
+use crate::error::{FileError, FileResult};
 
 pub const QUICKTIME_SIGN_1: [u8; 4] = [0x66, 0x74, 0x79, 0x70]; // ftyp
 pub const QUICKTIME_SIGN_2: [u8; 4] = [0x6d, 0x64, 0x61, 0x74]; // mdat
@@ -8,34 +11,49 @@ pub const QUICKTIME_SIGN_2: [u8; 4] = [0x6d, 0x64, 0x61, 0x74]; // mdat
 struct Atom {
     name: String,
     start_index: u64,
+    /// 8 bytes, or 16 when the size is stored as a 64-bit number.
+    header_len: u64,
     size: u64,
 }
 
-pub fn parse_datetime(path: &PathBuf) -> Option<DateTime<Utc>> {
-    let reader = File::open(path).ok()?;
+/// Returns `Ok(None)` when the file has no media header or its creation time is unset.
+pub fn parse_datetime(path: &Path) -> FileResult<Option<DateTime<Utc>>> {
+    let reader = File::open(path)?;
     let atom = find_atom_recursively(
         &reader,
         vec!["moov", "trak", "mdia", "mdhd"],
         0,
-        reader.metadata().ok()?.len(),
+        reader.metadata()?.len(),
     )?;
+    let atom = match atom {
+        Some(atom) => atom,
+        None => return Ok(None),
+    };
 
     let mut buffer: [u8; 8] = [0; 8];
-    reader
-        .read_exact_at(&mut buffer, atom.start_index + 8)
-        .ok()?;
-    // println!("{:?}", buffer);
+    reader.read_exact_at(&mut buffer, atom.start_index + atom.header_len)?;
     let seconds = u64::from_be_bytes(buffer);
     if seconds == 0 {
-        return None;
+        return Ok(None);
+    }
+    // Further out than chrono can represent, the field is garbage
+    if seconds > (i64::MAX / 1000) as u64 {
+        return Err(FileError::Unsupported(format!(
+            "creation time of {} seconds",
+            seconds
+        )));
     }
     let datetime = Utc
         .with_ymd_and_hms(1904, 1, 1, 0, 0, 0)
         .unwrap()
-        .checked_add_signed(Duration::seconds(seconds as i64))?;
-    // println!("{} {} {}", atom.name, atom.start_index, atom.size);
-    // println!("{}", datetime.to_rfc3339());
-    return Some(datetime);
+        .checked_add_signed(Duration::seconds(seconds as i64));
+    trace!(
+        "QuickTime {} atom at {}, {} bytes",
+        atom.name,
+        atom.start_index,
+        atom.size
+    );
+    return Ok(datetime);
 }
 
 fn find_atom_recursively(
@@ -43,40 +61,68 @@ fn find_atom_recursively(
     atom_names: Vec<&str>,
     start_index: u64,
     end_index: u64,
-) -> Option<Atom> {
-    // println!("{} {} {}", atom_names.get(0)?, start_index, end_index);
+) -> FileResult<Option<Atom>> {
+    trace!(
+        "Searching for the {} atom between {} and {}",
+        atom_names[0],
+        start_index,
+        end_index
+    );
     let mut index = start_index;
     while index < end_index {
-        let atom = get_atom(reader, index)?;
-        if atom.name == atom_names.get(0)?.to_owned() {
+        let atom = get_atom(reader, index, end_index)?;
+        if atom.name == atom_names[0] {
             if atom_names.len() == 1 {
-                return Some(Atom {
-                    name: atom.name,
-                    start_index: index,
-                    size: atom.size,
-                });
+                return Ok(Some(atom));
             }
             let mut new_atom_names = atom_names.clone();
             new_atom_names.remove(0);
-            let value = find_atom_recursively(reader, new_atom_names, index + 8, index + atom.size);
+            let value = find_atom_recursively(
+                reader,
+                new_atom_names,
+                index + atom.header_len,
+                index + atom.size,
+            )?;
             if value.is_some() {
-                return Some(value?);
+                return Ok(value);
             }
         }
         index += atom.size;
     }
-    return None;
+    return Ok(None);
 }
 
-fn get_atom(reader: &File, index: u64) -> Option<Atom> {
+/// Reads the atom header at `index`. A size of 0 means the atom runs to the end of its parent,
+/// a size of 1 that the real size follows as a 64-bit number. The atom has to fit within its
+/// parent, so the caller can step over it without overflowing.
+fn get_atom(reader: &File, index: u64, end_index: u64) -> FileResult<Atom> {
     let mut buffer: [u8; 4] = [0; 4];
-    reader.read_exact_at(&mut buffer, index).ok()?;
-    let size = u32::from_be_bytes(buffer) as u64;
-    reader.read_exact_at(&mut buffer, index + 4).ok()?;
-    let name = String::from_utf8(buffer.to_vec()).ok()?;
-    return Some(Atom {
+    reader.read_exact_at(&mut buffer, index)?;
+    let mut size = u32::from_be_bytes(buffer) as u64;
+    reader.read_exact_at(&mut buffer, index + 4)?;
+    let name = String::from_utf8_lossy(&buffer).into_owned();
+    let mut header_len = 8;
+    if size == 0 {
+        size = end_index - index;
+    } else if size == 1 {
+        let mut large_size: [u8; 8] = [0; 8];
+        reader.read_exact_at(&mut large_size, index + 8)?;
+        size = u64::from_be_bytes(large_size);
+        header_len = 16;
+    }
+    if size < header_len {
+        return Err(FileError::Unsupported(format!(
+            "atom `{}` is smaller than its header",
+            name
+        )));
+    }
+    if size > end_index - index {
+        return Err(FileError::Truncated);
+    }
+    return Ok(Atom {
         name,
         start_index: index,
+        header_len,
         size,
     });
 }
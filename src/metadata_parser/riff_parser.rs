@@ -4,8 +4,12 @@ use std::{
 };
 
 use chrono::{DateTime, Utc};
+use log::trace;
 
-use crate::util::{self, io::StepableBuffReader};
+use crate::{
+    error::{FileError, FileResult},
+    util::{self, io::StepableBuffReader},
+};
 
 pub const RIFF_SIGN: &[u8] = "RIFF".as_bytes();
 
@@ -25,7 +29,6 @@ mod constants {
 struct Chunk {
     container: bool,
     id: String,
-    start_index: usize,
     size: usize,
 }
 
@@ -35,78 +38,82 @@ impl PartialEq<constants::ChunkType<'_>> for Chunk {
     }
 }
 
-pub fn parse_datetime<R: Read + Seek>(reader: &mut StepableBuffReader<R>) -> Option<DateTime<Utc>> {
-    let riff_length = reader.read_u32(false) as usize;
+/// Returns `Ok(None)` when the file has no IDIT datetime before the movie data.
+pub fn parse_datetime<R: Read + Seek>(
+    reader: &mut StepableBuffReader<R>,
+) -> FileResult<Option<DateTime<Utc>>> {
+    let riff_length = reader.read_u32(false)? as usize;
     // reader.increment_by(4); // Riff length
-    reader.increment_by(4); // Riff type
+    reader.increment_by(4)?; // Riff type
     let chunk = find_chunk(
         reader,
         vec![constants::LIST_HDRL, constants::IDIT],
         riff_length,
-    );
+    )?;
     match chunk {
-        Ok(chunk) if chunk.is_some() => {
-            let buffer = reader.read(chunk?.size);
+        Some(chunk) => {
+            let buffer = reader.read(chunk.size)?;
             let mut dt = from_utf8(buffer.as_slice()).unwrap_or("").trim();
             dt = dt.trim_matches(&[char::from(0), char::from(10), char::from(13)] as &[_]);
-            return Some(util::parse_datetime(dt)?);
+            return Ok(util::parse_datetime(dt));
         }
-        _ => None,
+        None => return Ok(None),
     }
 }
 
+/// Searches for the nested chunks, stopping at the movie data where no header chunks follow.
 fn find_chunk<'a, R: Read + Seek>(
     reader: &'a mut StepableBuffReader<R>,
     chunk_tags: Vec<constants::ChunkType<'a>>,
     chunk_length: usize,
-) -> Result<Option<Chunk>, ()> {
+) -> FileResult<Option<Chunk>> {
     let mut offset = 0;
     while offset < chunk_length {
-        let chunk = get_chunk(reader);
-        // println!("Chunk ({}, {})", chunk.id, chunk.container);
-        if &chunk == chunk_tags.get(0).unwrap() {
+        let chunk = get_chunk(reader)?;
+        trace!("RIFF chunk {} (container: {})", chunk.id, chunk.container);
+        if &chunk == chunk_tags.first().unwrap() {
             if chunk_tags.len() == 1 {
                 return Ok(Some(chunk));
             }
             let mut new_chunk_tags = chunk_tags.clone();
             new_chunk_tags.remove(0);
-            // println!("going in!");
-            let result = find_chunk(reader, new_chunk_tags, chunk.size);
-            match result {
-                Ok(result) if result.is_some() => return Ok(result),
-                Err(_) => return Err(()),
-                _ => (),
+            let result = find_chunk(reader, new_chunk_tags, chunk.size)?;
+            if result.is_some() {
+                return Ok(result);
             }
         } else {
             if chunk == constants::MOVI {
-                return Err(());
+                return Ok(None);
             }
-            // println!("Incrementing by: {}", chunk.size);
             offset += chunk.size;
-            reader.increment_by(chunk.size);
+            if !reader.increment_by(chunk.size)? {
+                return Err(FileError::Truncated);
+            }
         }
     }
-    // println!("done");
     Ok(None)
 }
 
-fn get_chunk<R: Read + Seek>(reader: &mut StepableBuffReader<R>) -> Chunk {
-    let chunk_tag = String::from_utf8(reader.read(4).to_vec()).unwrap();
-    let size = reader.read_u32(false) as usize;
+fn get_chunk<R: Read + Seek>(reader: &mut StepableBuffReader<R>) -> FileResult<Chunk> {
+    let chunk_tag = chunk_id(reader)?;
+    let size = reader.read_u32(false)? as usize;
     if chunk_tag == "RIFF" || chunk_tag == "LIST" {
-        let container_type = String::from_utf8(reader.read(4).to_vec()).unwrap();
-        return Chunk {
+        let container_type = chunk_id(reader)?;
+        return Ok(Chunk {
             container: true,
             id: container_type,
-            start_index: reader.total_offset,
-            size: size - 4,
-        };
+            size: size.checked_sub(4).ok_or(FileError::Truncated)?,
+        });
     } else {
-        return Chunk {
+        return Ok(Chunk {
             container: false,
             id: chunk_tag,
-            start_index: reader.total_offset,
             size,
-        };
+        });
     }
 }
+
+fn chunk_id<R: Read + Seek>(reader: &mut StepableBuffReader<R>) -> FileResult<String> {
+    return String::from_utf8(reader.read(4)?)
+        .map_err(|_| FileError::BadSignature("a RIFF chunk id"));
+}
@@ -1,32 +1,126 @@
 use std::{collections::HashMap, fs, path::PathBuf};
 
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    duplicates::{DuplicateCheck, KeeperPolicy},
+    index_cache::IndexCache,
+    journal::unique_journal_name,
+    transfer::{Preserve, TransferOptions},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum FileTypes {
     DOCUMENT,
     IMAGE,
     VIDEO,
 }
 
+/// Identifies the content of a file with as little reading as possible. Files whose content
+/// size is unique are never read, files whose partial hash is unique are never fully hashed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentKey {
+    Size(u64),
+    Partial(u64, u64),
+    Full(u64),
+}
+
+impl ContentKey {
+    pub fn full_hash(&self) -> Option<u64> {
+        return match self {
+            ContentKey::Full(hash) => Some(*hash),
+            _ => None,
+        };
+    }
+}
+
+/// Content key to every indexed file with that content.
+pub type FileIndex = HashMap<ContentKey, Vec<PathBuf>>;
+
 pub enum MediaType {
     IMAGE,
     VIDEO,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Action {
     Copy,
     Delete,
     Move,
 }
 
-pub struct Config<'a> {
-    pub source_dir: String,
+/// How files from the source folders are brought into the library.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    Copy,
+    Move,
+}
+
+pub struct Config {
+    pub source_dirs: Vec<String>,
     pub doc_dir: String,
     pub image_dir: String,
     pub video_dir: String,
-    pub file_exts: HashMap<&'a str, &'a FileTypes>,
+    pub file_exts: HashMap<String, FileTypes>,
+    pub import_mode: ImportMode,
+    /// Name of dated media, `{field}` placeholders and `strftime` specifiers, see `template`.
+    pub name_template: String,
+    /// Template for the folders below the destination folder, empty to keep files directly in it.
+    pub layout: String,
+    /// Only print the planned actions instead of executing them.
+    pub dry_run: bool,
+    /// Write the planned actions to this file instead of executing them.
+    pub plan_out: Option<PathBuf>,
+    /// Where to record completed operations, defaults to a new file under the library root.
+    pub journal: Option<PathBuf>,
+    /// Also write the run report to this JSON file.
+    pub report_out: Option<PathBuf>,
+    /// Remove deleted files for good instead of moving them to the trash.
+    pub permanent_delete: bool,
+    /// Compare copies with their source byte for byte before putting them in place.
+    pub verify_copies: bool,
+    /// What copies take over from their source.
+    pub preserve: Vec<Preserve>,
+    /// Set the modification time of copied and moved files to their metadata datetime.
+    pub mtime_from_metadata: bool,
+    /// How long trashed files are kept before `purge-trash` removes them.
+    pub trash_retention_days: u32,
+    /// Decides which file of a duplicate group is kept.
+    pub keeper_policy: KeeperPolicy,
+    /// How duplicates are confirmed before they are deleted.
+    pub duplicate_check: DuplicateCheck,
+    /// Also confirm duplicates before new files are skipped.
+    pub confirm_skips: bool,
+    /// Ignore the cached hashes and index every library file again.
+    pub rebuild_index: bool,
 }
 
-impl Config<'_> {
+impl Config {
+    pub fn default_file_exts() -> HashMap<String, FileTypes> {
+        return [
+            ("doc", FileTypes::DOCUMENT),
+            ("docx", FileTypes::DOCUMENT),
+            ("pdf", FileTypes::DOCUMENT),
+            ("ppt", FileTypes::DOCUMENT),
+            ("pptx", FileTypes::DOCUMENT),
+            ("xls", FileTypes::DOCUMENT),
+            ("xlsx", FileTypes::DOCUMENT),
+            ("jpeg", FileTypes::IMAGE),
+            ("jpg", FileTypes::IMAGE),
+            ("png", FileTypes::IMAGE),
+            ("avi", FileTypes::VIDEO),
+            ("mov", FileTypes::VIDEO),
+            ("mp4", FileTypes::VIDEO),
+        ]
+        .iter()
+        .map(|(e, f)| return (e.to_string(), *f))
+        .collect();
+    }
+
     pub fn create_folders(&self) {
         let _ = fs::create_dir_all(&self.doc_dir);
         let _ = fs::create_dir_all(&self.image_dir);
@@ -35,6 +129,51 @@ impl Config<'_> {
         let _ = fs::create_dir_all(format!("{}_temp", &self.video_dir));
     }
 
+    /// How duplicates are confirmed before new files are skipped.
+    pub fn skip_check(&self) -> DuplicateCheck {
+        return match self.confirm_skips {
+            true => self.duplicate_check,
+            false => DuplicateCheck::Off,
+        };
+    }
+
+    /// Whether the run stops after planning, leaving the file system untouched.
+    pub fn only_plans(&self) -> bool {
+        return self.dry_run || self.plan_out.is_some();
+    }
+
+    /// The closest folder containing all destination folders, used for the `.sorter` state folder.
+    pub fn get_library_root(&self) -> PathBuf {
+        let dirs = [&self.doc_dir, &self.image_dir, &self.video_dir].map(PathBuf::from);
+        let mut root = dirs[0].clone();
+        while !dirs.iter().all(|dir| dir.starts_with(&root)) {
+            root.pop();
+        }
+        if root.as_os_str().is_empty() || root.parent().is_none() {
+            return dirs[1].clone();
+        }
+        return root;
+    }
+
+    pub fn default_journal_path(&self) -> PathBuf {
+        return self
+            .get_library_root()
+            .join(".sorter")
+            .join("journals")
+            .join(unique_journal_name());
+    }
+
+    pub fn index_cache_path(&self) -> PathBuf {
+        return self.get_library_root().join(".sorter").join("index.json");
+    }
+
+    pub fn load_index_cache(&self) -> IndexCache {
+        return match self.rebuild_index {
+            true => IndexCache::empty(&self.index_cache_path()),
+            false => IndexCache::load(&self.index_cache_path()),
+        };
+    }
+
     pub fn get_destination_folders(&self) -> Vec<PathBuf> {
         return vec![
             self.doc_dir.clone().into(),
@@ -44,4 +183,23 @@ impl Config<'_> {
             (self.video_dir.clone() + "_temp").into(),
         ];
     }
+
+    pub fn get_temp_folders(&self) -> Vec<PathBuf> {
+        return vec![
+            (self.image_dir.clone() + "_temp").into(),
+            (self.video_dir.clone() + "_temp").into(),
+        ];
+    }
+
+    pub fn transfer_options(&self) -> TransferOptions {
+        return TransferOptions {
+            verify_copies: self.verify_copies,
+            preserve: self.preserve.clone(),
+            mtime_from_metadata: self.mtime_from_metadata,
+        };
+    }
+
+    pub fn get_source_folders(&self) -> Vec<PathBuf> {
+        return self.source_dirs.iter().map(PathBuf::from).collect();
+    }
 }
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Action, Config};
+
+/// One row of an exported plan. The hash is the source's content hash at planning time and
+/// is checked again before the plan is applied.
+#[derive(Deserialize, Serialize)]
+pub struct PlanEntry {
+    pub action: Action,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub hash: String,
+}
+
+enum PlanFormat {
+    Csv,
+    Json,
+}
+
+fn plan_format(path: &Path) -> PlanFormat {
+    return match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => PlanFormat::Csv,
+        _ => PlanFormat::Json,
+    };
+}
+
+/// Writes the plan as CSV when the file ends in `.csv`, otherwise as JSON.
+pub fn write_plan(
+    path: &Path,
+    actions: &[(Action, PathBuf, PathBuf)],
+    hashes: &HashMap<PathBuf, u64>,
+) -> Result<(), String> {
+    let mut entries: Vec<PlanEntry> = actions
+        .iter()
+        .map(|(act, src, dest)| PlanEntry {
+            action: *act,
+            source: src.clone(),
+            destination: dest.clone(),
+            hash: hashes
+                .get(src)
+                .map(|h| format!("{:016x}", h))
+                .unwrap_or_default(),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let result = match plan_format(path) {
+        PlanFormat::Json => serde_json::to_writer_pretty(file, &entries).map_err(|e| e.to_string()),
+        PlanFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            entries
+                .iter()
+                .try_for_each(|entry| writer.serialize(entry))
+                .and_then(|_| writer.flush().map_err(csv::Error::from))
+                .map_err(|e| e.to_string())
+        }
+    };
+    return result.map_err(|e| format!("{}: {}", path.display(), e));
+}
+
+pub fn read_plan(path: &Path) -> Result<Vec<PlanEntry>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let result = match plan_format(path) {
+        PlanFormat::Json => serde_json::from_reader(file).map_err(|e| e.to_string()),
+        PlanFormat::Csv => csv::Reader::from_reader(file)
+            .deserialize()
+            .collect::<Result<Vec<PlanEntry>, csv::Error>>()
+            .map_err(|e| e.to_string()),
+    };
+    return result.map_err(|e| format!("{}: {}", path.display(), e));
+}
+
+/// Prints the planned actions grouped by kind, with undated media (headed for a `_temp`
+/// folder) listed separately so they can be reviewed before anything is touched.
+pub fn print_plan(actions: &[(Action, PathBuf, PathBuf)], config: &Config) {
+    let temp_folders = config.get_temp_folders();
+    let mut total_files = 0;
+    let mut total_bytes = 0;
+
+    for kind in [Action::Copy, Action::Move, Action::Delete] {
+        let mut group: Vec<&(Action, PathBuf, PathBuf)> =
+            actions.iter().filter(|(act, _, _)| *act == kind).collect();
+        if group.is_empty() {
+            continue;
+        }
+        group.sort_by(|a, b| a.1.cmp(&b.1));
+        let (undated, dated): (Vec<_>, Vec<_>) = group.into_iter().partition(|(act, _, dest)| {
+            *act != Action::Delete && temp_folders.iter().any(|dir| dest.parent() == Some(dir))
+        });
+
+        let mut group_bytes = 0;
+        println!("{:?} ({} files)", kind, dated.len() + undated.len());
+        for (act, src, dest) in &dated {
+            group_bytes += print_action(act, src, dest);
+        }
+        if !undated.is_empty() {
+            println!(
+                "  No datetime found, going to _temp ({} files):",
+                undated.len()
+            );
+            for (act, src, dest) in &undated {
+                group_bytes += print_action(act, src, dest);
+            }
+        }
+        println!(
+            "  Total: {} files, {}\n",
+            dated.len() + undated.len(),
+            HumanBytes(group_bytes)
+        );
+        total_files += dated.len() + undated.len();
+        total_bytes += group_bytes;
+    }
+
+    println!(
+        "Planned {} actions on {}, nothing was changed (dry run)",
+        total_files,
+        HumanBytes(total_bytes)
+    );
+}
+
+fn print_action(act: &Action, src: &Path, dest: &Path) -> u64 {
+    let size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+    match act {
+        Action::Delete => println!("  {} ({})", src.display(), HumanBytes(size)),
+        _ => println!(
+            "  {} -> {} ({})",
+            src.display(),
+            dest.display(),
+            HumanBytes(size)
+        ),
+    }
+    return size;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        let actions = [
+            (
+                Action::Move,
+                PathBuf::from("b, \"quoted\".jpg"),
+                PathBuf::from("lib/b.jpg"),
+            ),
+            (
+                Action::Copy,
+                PathBuf::from("a.jpg"),
+                PathBuf::from("lib/a.jpg"),
+            ),
+        ];
+        let hashes = HashMap::from([(PathBuf::from("a.jpg"), 0xabc)]);
+        write_plan(&path, &actions, &hashes).unwrap();
+
+        let entries = read_plan(&path).unwrap();
+        let rows: Vec<(Action, &Path, &Path, &str)> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.action,
+                    e.source.as_path(),
+                    e.destination.as_path(),
+                    e.hash.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (
+                    Action::Copy,
+                    Path::new("a.jpg"),
+                    Path::new("lib/a.jpg"),
+                    "0000000000000abc"
+                ),
+                (
+                    Action::Move,
+                    Path::new("b, \"quoted\".jpg"),
+                    Path::new("lib/b.jpg"),
+                    ""
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_plans_round_trip() {
+        round_trip("plan.json");
+    }
+
+    #[test]
+    fn csv_plans_round_trip() {
+        round_trip("plan.CSV");
+    }
+}
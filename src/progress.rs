@@ -0,0 +1,92 @@
+use std::sync::{LazyLock, Mutex};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+const SPINNER_TEMPLATE: &str = "{spinner} [{elapsed_precise}] {wide_msg}";
+const FILES_TEMPLATE: &str =
+    "{spinner} [{elapsed_precise}] {wide_msg} [{bar:20}] ({pos}/{len}, ETA {eta})";
+const BYTES_TEMPLATE: &str = "{spinner} [{elapsed_precise}] {wide_msg} [{bar:20}] \
+                              ({bytes}/{total_bytes}, {binary_bytes_per_sec}, ETA {eta})";
+const OVERALL_TEMPLATE: &str = "  [{elapsed_precise}] {wide_msg} [{bar:20}] ({pos}/{len} phases)";
+
+static MULTI: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+static OVERALL: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Every progress bar is drawn through this, so log lines printed to the console are written
+/// above the bars instead of through them.
+pub fn multi() -> &'static MultiProgress {
+    return &MULTI;
+}
+
+/// Runs `print` with the bars cleared and draws them again afterwards, so output printed while
+/// bars are ticking is not drawn over.
+pub fn suspend<R>(print: impl FnOnce() -> R) -> R {
+    return multi().suspend(print);
+}
+
+/// How the progress of a phase is counted.
+pub enum Unit {
+    Files,
+    /// Bytes read or written, shown with throughput.
+    Bytes,
+}
+
+/// Shows an overall bar below the phase bars, advanced as every phase finishes.
+pub fn start_overall(phases: u64) {
+    let bar = multi().add(ProgressBar::new(phases));
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template(OVERALL_TEMPLATE)
+            .unwrap(),
+    );
+    bar.set_message("Overall");
+    bar.enable_steady_tick(std::time::Duration::from_millis(200));
+    *OVERALL.lock().unwrap() = Some(bar);
+}
+
+pub fn finish_overall() {
+    if let Some(bar) = OVERALL.lock().unwrap().take() {
+        bar.finish_and_clear();
+    }
+}
+
+/// A bar for the next phase, a spinner when its length is unknown.
+pub fn start_phase(message: &str, len: Option<u64>, unit: Unit) -> ProgressBar {
+    let bar = ProgressBar::new_spinner();
+    let bar = match OVERALL.lock().unwrap().as_ref() {
+        Some(overall) => {
+            overall.set_message(format!("Overall: {}", message.trim().to_lowercase()));
+            multi().insert_before(overall, bar)
+        }
+        None => multi().add(bar),
+    };
+    let template = match (len, unit) {
+        (None, _) => SPINNER_TEMPLATE,
+        (Some(_), Unit::Files) => FILES_TEMPLATE,
+        (Some(_), Unit::Bytes) => BYTES_TEMPLATE,
+    };
+    bar.set_style(ProgressStyle::default_spinner().template(template).unwrap());
+    bar.set_message(message.to_owned());
+    match len {
+        Some(len) => bar.set_length(len),
+        None => bar.enable_steady_tick(std::time::Duration::from_millis(200)),
+    }
+    return bar;
+}
+
+/// Leaves the phase on screen with `message` and advances the overall bar.
+pub fn finish_phase(bar: &ProgressBar, message: String) {
+    bar.set_style(
+        ProgressStyle::default_spinner()
+            .template(SPINNER_TEMPLATE)
+            .unwrap(),
+    );
+    bar.finish();
+    bar.set_message(message);
+    if let Some(overall) = OVERALL.lock().unwrap().as_ref() {
+        if overall.position() == overall.length().unwrap_or(0) {
+            overall.inc_length(1);
+        }
+        overall.inc(1);
+    }
+}
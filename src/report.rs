@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use indicatif::HumanBytes;
+use log::warn;
+use serde::Serialize;
+
+use crate::{
+    duplicates::DuplicateGroup,
+    error::FileError,
+    metadata_parser::datetime_parser::MetadataFormat,
+    models::{Action, FileTypes},
+};
+
+/// Collects what a run did and what went wrong with individual files, so one bad file is
+/// reported at the end instead of aborting everything. Printed as a summary and optionally
+/// written as JSON.
+#[derive(Default)]
+pub struct RunReport {
+    errors: Mutex<Vec<(PathBuf, String)>>,
+    stats: Mutex<RunStats>,
+}
+
+#[derive(Default, Serialize)]
+struct RunStats {
+    /// Supported files found in each scanned folder.
+    scanned_per_folder: BTreeMap<PathBuf, usize>,
+    scanned_per_type: BTreeMap<String, usize>,
+    /// Images and videos with and without a metadata datetime.
+    dated: usize,
+    undated: usize,
+    duplicate_groups: usize,
+    /// Files of the duplicate groups other than the keepers.
+    duplicate_files: usize,
+    /// Executed actions by kind.
+    actions: BTreeMap<String, usize>,
+    bytes_copied: u64,
+    bytes_moved: u64,
+    /// Copies and moves left out because an identical file already was at the destination.
+    skipped_identical: usize,
+    /// Media files by the metadata parser that read them, this run or a cached earlier one.
+    parsers: BTreeMap<String, ParserCoverage>,
+}
+
+#[derive(Default, Serialize)]
+struct ParserCoverage {
+    files: usize,
+    /// Files the parser found a datetime in.
+    dated: usize,
+}
+
+#[derive(Serialize)]
+struct ReportJson<'a> {
+    #[serde(flatten)]
+    stats: &'a RunStats,
+    errors: Vec<ErrorJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct ErrorJson<'a> {
+    path: &'a Path,
+    error: &'a str,
+}
+
+impl RunReport {
+    pub fn record(&self, path: &Path, error: &FileError) {
+        self.record_message(path, error.to_string());
+    }
+
+    pub fn record_message(&self, path: &Path, message: String) {
+        warn!("{}: {}", path.display(), message);
+        self.errors
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), message));
+    }
+
+    pub fn has_errors(&self) -> bool {
+        return !self.errors.lock().unwrap().is_empty();
+    }
+
+    /// Counts a supported file found in `folder`, with whether it has a datetime for media.
+    pub fn record_scanned(&self, folder: &Path, file_type: FileTypes, dated: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        *stats
+            .scanned_per_folder
+            .entry(folder.to_path_buf())
+            .or_default() += 1;
+        *stats
+            .scanned_per_type
+            .entry(format!("{:?}", file_type).to_lowercase())
+            .or_default() += 1;
+        match (file_type, dated) {
+            (FileTypes::DOCUMENT, _) => {}
+            (_, true) => stats.dated += 1,
+            (_, false) => stats.undated += 1,
+        }
+    }
+
+    pub fn record_parsed(&self, format: MetadataFormat, dated: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        let coverage = stats.parsers.entry(format.name().to_owned()).or_default();
+        coverage.files += 1;
+        coverage.dated += dated as usize;
+    }
+
+    /// Counts the groups with files to drop, groups of hash collisions only have no duplicates.
+    pub fn record_duplicates(&self, groups: &[DuplicateGroup]) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.duplicate_groups += groups.iter().filter(|g| !g.duplicates.is_empty()).count();
+        stats.duplicate_files += groups.iter().map(|g| g.duplicates.len()).sum::<usize>();
+    }
+
+    /// Counts an executed action, `size` being the size of the copied or moved file.
+    pub fn record_action(&self, action: Action, size: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        *stats
+            .actions
+            .entry(format!("{:?}", action).to_lowercase())
+            .or_default() += 1;
+        match action {
+            Action::Copy => stats.bytes_copied += size,
+            Action::Move => stats.bytes_moved += size,
+            Action::Delete => {}
+        }
+    }
+
+    pub fn record_skipped(&self) {
+        self.stats.lock().unwrap().skipped_identical += 1;
+    }
+
+    pub fn print_summary(&self) {
+        let stats = self.stats.lock().unwrap();
+        let scanned: usize = stats.scanned_per_type.values().sum();
+        println!("Run summary");
+        println!("  {:<28} {:>8}", "Files scanned", scanned);
+        for (folder, count) in &stats.scanned_per_folder {
+            println!("    {:<26} {:>8}", folder.display(), count);
+        }
+        for (file_type, count) in &stats.scanned_per_type {
+            println!("    {:<26} {:>8}", file_type, count);
+        }
+        println!("  {:<28} {:>8}", "Dated media", stats.dated);
+        println!("  {:<28} {:>8}", "Undated media", stats.undated);
+        println!(
+            "  {:<28} {:>8}  in {} groups",
+            "Duplicates", stats.duplicate_files, stats.duplicate_groups
+        );
+        for action in ["copy", "move", "delete"] {
+            let count = stats.actions.get(action).copied().unwrap_or(0);
+            let bytes = match action {
+                "copy" => format!("  {}", HumanBytes(stats.bytes_copied)),
+                "move" => format!("  {}", HumanBytes(stats.bytes_moved)),
+                _ => String::new(),
+            };
+            println!(
+                "  {:<28} {:>8}{}",
+                format!("Actions: {}", action),
+                count,
+                bytes
+            );
+        }
+        println!(
+            "  {:<28} {:>8}",
+            "Identical at destination", stats.skipped_identical
+        );
+        for (parser, coverage) in &stats.parsers {
+            println!(
+                "  {:<28} {:>8}  of {} dated ({:.0}%)",
+                format!("Parser: {}", parser),
+                coverage.dated,
+                coverage.files,
+                100.0 * coverage.dated as f64 / coverage.files as f64
+            );
+        }
+        println!(
+            "  {:<28} {:>8}",
+            "Failed files",
+            self.errors.lock().unwrap().len()
+        );
+    }
+
+    /// Prints every failed file, sorted by path.
+    pub fn print_errors(&self) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.is_empty() {
+            return;
+        }
+        errors.sort();
+        println!("{} files failed:", errors.len());
+        for (path, message) in errors.iter() {
+            println!("  {}: {}", path.display(), message);
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<(), String> {
+        let stats = self.stats.lock().unwrap();
+        let mut errors = self.errors.lock().unwrap();
+        errors.sort();
+        let report = ReportJson {
+            stats: &stats,
+            errors: errors
+                .iter()
+                .map(|(path, error)| ErrorJson { path, error })
+                .collect(),
+        };
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &report)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        return writer
+            .flush()
+            .map_err(|e| format!("{}: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(duplicates: &[&str], collisions: &[&str]) -> DuplicateGroup {
+        return DuplicateGroup {
+            hash: 0,
+            keeper: PathBuf::from("keeper"),
+            duplicates: duplicates.iter().map(PathBuf::from).collect(),
+            collisions: collisions.iter().map(PathBuf::from).collect(),
+        };
+    }
+
+    #[test]
+    fn groups_of_only_collisions_are_no_duplicates() {
+        let report = RunReport::default();
+        report.record_duplicates(&[group(&["a", "b"], &[]), group(&[], &["c"])]);
+        report.record_duplicates(&[group(&["d"], &["e"])]);
+        let stats = report.stats.lock().unwrap();
+        assert_eq!(stats.duplicate_groups, 2);
+        assert_eq!(stats.duplicate_files, 3);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use image::ImageReader;
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::{
+    index_cache::IndexEntry,
+    models::Config,
+    progress,
+    report::RunReport,
+    sorting::{hash_files, replace_index_entries, save_index_cache, scan_files},
+};
+
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+/// Extensions of the images that can be decoded.
+const DECODABLE_EXTS: [&str; 3] = ["jpg", "jpeg", "png"];
+/// How far into a file EXIF data is looked for.
+const EXIF_SEARCH_SIZE: u64 = 64 * 1024;
+
+/// Groups library images whose perceptual hashes differ in at most `max_distance` bits,
+/// which catches resized and recompressed copies that have a different content hash.
+/// Perceptual hashes are kept in the library index, so only new images are decoded.
+pub fn find_similar(config: Config, max_distance: u32) -> bool {
+    let report = RunReport::default();
+    progress::start_overall(2);
+    let mut cache = config.load_index_cache();
+    let mut files = scan_files(
+        config.get_destination_folders(),
+        &config.file_exts,
+        true,
+        Some(&cache),
+        &report,
+    );
+    // Images that cannot be decoded simply get no perceptual hash
+    hash_files(
+        &mut files,
+        "Computing perceptual hashes of new images",
+        &report,
+        |path, entry| entry.perceptual_hash.is_none() && is_decodable(path),
+        |entry| entry.size,
+        |path, entry| {
+            entry.perceptual_hash = perceptual_hash(path);
+            return Ok(());
+        },
+    );
+    replace_index_entries(&mut cache, &files);
+    save_index_cache(&cache);
+    progress::finish_overall();
+
+    let clusters = cluster_images(&files, max_distance);
+    if clusters.is_empty() {
+        println!("No similar images found");
+    } else {
+        print_clusters(clusters);
+    }
+    report.print_errors();
+    return !report.has_errors();
+}
+
+fn print_clusters(clusters: Vec<Vec<&PathBuf>>) {
+    println!("Found {} clusters of similar images", clusters.len());
+    for cluster in clusters {
+        println!();
+        let mut images: Vec<ImageInfo> = cluster.into_iter().map(ImageInfo::read).collect();
+        // Likely originals first: largest resolution, then largest file
+        images.sort_by(|a, b| {
+            b.pixels()
+                .cmp(&a.pixels())
+                .then(b.size.cmp(&a.size))
+                .then(a.path.cmp(b.path))
+        });
+        for image in images {
+            let resolution = match image.dimensions {
+                Some((width, height)) => format!("{}x{}", width, height),
+                None => "unknown".to_owned(),
+            };
+            let exif = match image.has_exif {
+                true => "EXIF",
+                false => "no EXIF",
+            };
+            println!(
+                "  {:>11}  {:>11}  {:<7}  {}",
+                resolution,
+                HumanBytes(image.size).to_string(),
+                exif,
+                image.path.display()
+            );
+        }
+    }
+}
+
+fn is_decodable(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| DECODABLE_EXTS.contains(&e.to_ascii_lowercase().as_str()));
+}
+
+/// Difference hash: one bit per horizontally adjacent pixel pair of a 9x8 grayscale thumbnail.
+/// Returns `None` for images that cannot be decoded.
+fn perceptual_hash(path: &Path) -> Option<u64> {
+    let image = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()?;
+    let thumbnail = image.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = thumbnail.get_pixel(x, y)[0] < thumbnail.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    return Some(hash);
+}
+
+/// Links every pair of images within `max_distance` and returns the connected groups with
+/// more than one image, ordered by their first path. Pairs are linked as they are found, as
+/// at large distances nearly every pair matches.
+fn cluster_images(files: &[(PathBuf, IndexEntry)], max_distance: u32) -> Vec<Vec<&PathBuf>> {
+    let images: Vec<(&PathBuf, u64)> = files
+        .iter()
+        .filter_map(|(path, entry)| Some((path, entry.perceptual_hash?)))
+        .collect();
+    let mut sets = (0..images.len())
+        .into_par_iter()
+        .fold(
+            || DisjointSets::new(images.len()),
+            |mut sets, i| {
+                for j in (i + 1)..images.len() {
+                    if (images[i].1 ^ images[j].1).count_ones() <= max_distance {
+                        sets.union(i, j);
+                    }
+                }
+                return sets;
+            },
+        )
+        .reduce(|| DisjointSets::new(images.len()), DisjointSets::merge);
+
+    let mut clusters: Vec<Vec<&PathBuf>> = vec![Vec::new(); images.len()];
+    for (i, (path, _)) in images.iter().enumerate() {
+        clusters[sets.find(i)].push(path);
+    }
+    let mut clusters: Vec<Vec<&PathBuf>> = clusters
+        .into_iter()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|mut cluster| {
+            cluster.sort();
+            cluster
+        })
+        .collect();
+    clusters.sort();
+    return clusters;
+}
+
+/// Union-find over the image indices, each set rooted at its lowest index.
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        return DisjointSets {
+            parents: (0..len).collect(),
+        };
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        return i;
+    }
+
+    fn union(&mut self, i: usize, j: usize) {
+        let (root_i, root_j) = (self.find(i), self.find(j));
+        self.parents[root_i.max(root_j)] = root_i.min(root_j);
+    }
+
+    /// Joins the sets found by another thread into these.
+    fn merge(mut self, mut other: DisjointSets) -> Self {
+        for i in 0..other.parents.len() {
+            let root = other.find(i);
+            self.union(i, root);
+        }
+        return self;
+    }
+}
+
+struct ImageInfo<'a> {
+    path: &'a PathBuf,
+    dimensions: Option<(u32, u32)>,
+    size: u64,
+    has_exif: bool,
+}
+
+impl<'a> ImageInfo<'a> {
+    fn read(path: &'a PathBuf) -> Self {
+        return ImageInfo {
+            path,
+            dimensions: image::image_dimensions(path).ok(),
+            size: path.metadata().map(|m| m.len()).unwrap_or(0),
+            has_exif: has_exif(path),
+        };
+    }
+
+    fn pixels(&self) -> u64 {
+        return self
+            .dimensions
+            .map(|(width, height)| width as u64 * height as u64)
+            .unwrap_or(0);
+    }
+}
+
+/// Whether the JPEG has an Exif APP1 segment or the PNG an eXIf chunk near the start.
+fn has_exif(path: &Path) -> bool {
+    let mut header = Vec::new();
+    let read = File::open(path).and_then(|f| f.take(EXIF_SEARCH_SIZE).read_to_end(&mut header));
+    if read.is_err() {
+        return false;
+    }
+    let marker: &[u8] = match header.starts_with(&[0xFF, 0xD8]) {
+        true => b"Exif\0\0",
+        false => b"eXIf",
+    };
+    return header.windows(marker.len()).any(|window| window == marker);
+}
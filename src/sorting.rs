@@ -1,74 +1,563 @@
 use std::{
-    collections::HashMap,
-    fs::{self, File},
+    collections::{HashMap, HashSet},
+    fs, io,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use chrono::{DateTime, Local, Timelike, Utc};
+use indicatif::ParallelProgressIterator;
 use jwalk::WalkDir;
+use log::{debug, info, trace};
 use rayon::prelude::*;
-use xxhash_rust::xxh3::xxh3_64;
 
 use crate::{
-    metadata_parser::datetime_parser::read_datetime,
-    models::{Action, Config, FileTypes, MediaType},
-    util::io::StepableBuffReader,
+    duplicates::{
+        files_byte_identical, files_identical, find_duplicate_groups, report_duplicates,
+        DuplicateGroup,
+    },
+    error::FileResult,
+    hashing::{content_size, get_file_hash, partial_hash, partial_hash_len},
+    index_cache::{IndexCache, IndexEntry},
+    journal::{unique_journal_name, Journal},
+    metadata_parser::datetime_parser::{
+        read_capture_time, read_datetime, read_metadata, MediaMetadata,
+    },
+    models::{Action, Config, FileIndex, FileTypes, ImportMode, MediaType},
+    plan::{print_plan, read_plan, write_plan, PlanEntry},
+    progress::{self, Unit},
+    report::RunReport,
+    template::{
+        check_file_name, fill_sequence, has_sequence, render_layout, render_name, sequence_number,
+        uses_field, TemplateValues,
+    },
+    transfer::{copy_file, move_file, Preserve, TransferOptions},
+    trash::Trash,
 };
 
-pub fn sort_files(config: Config) {
-    // TODO: need to implement the command line code
-    // TODO: need to implement the interim folder feature
-    // all existing files should be processed
+pub fn sort_files(config: Config) -> bool {
+    // TODO: all existing files should be processed
     // should ignore file name numbering for the purpose of comparing the file in order to move/copy
-    // log the actions to a file
     // add a bunch of testing
-    // look into multistage progress bar
-    // maybe we should find a way to iterate over file size and not num files?
     // maybe actions should be split up (move, copy, delete)
     // look into faster file copy (esp for larger files)
-    let exis_files = index_files(config.get_destination_folders(), &config, true);
-    let mut actions = process_files(&exis_files, &config, None);
+    let report = RunReport::default();
+    // Indexing, hashing, processing existing and new files, then the file changes
+    progress::start_overall(6 + !config.only_plans() as u64);
+    let mut cache = config.load_index_cache();
+    let (exis_files, new_files, indexed) = index_library(&config, &mut cache, true, &report);
+    let exis_groups =
+        find_duplicate_groups(&exis_files, &config.keeper_policy, config.duplicate_check);
+    let mut actions = process_files(&exis_files, &exis_groups, &config, None, &indexed, &report);
 
-    let new_files = index_files(vec![(&config.source_dir).into()], &config, false);
-    actions.append(&mut process_files(&new_files, &config, Some(&exis_files)));
+    let new_groups = find_duplicate_groups(&new_files, &config.keeper_policy, config.skip_check());
+    actions.append(&mut process_files(
+        &new_files,
+        &new_groups,
+        &config,
+        Some(&exis_files),
+        &indexed,
+        &report,
+    ));
+    let actions = number_sequences(actions, &indexed);
 
-    for action in &actions {
-        println!("{:#?} {:#?} {:#?}", action.0, action.1, action.2)
+    progress::suspend(|| {
+        report_duplicates(&exis_groups, true, config.dry_run);
+        report_duplicates(&new_groups, false, config.dry_run);
+    });
+    report.record_duplicates(&exis_groups);
+    report.record_duplicates(&new_groups);
+    let ok = run_actions(&actions, &config, &indexed, &mut cache, &report);
+    return finish_run(&config, &report, ok);
+}
+
+pub fn dedupe_files(config: Config) -> bool {
+    let report = RunReport::default();
+    progress::start_overall(4 + !config.only_plans() as u64);
+    let mut cache = config.load_index_cache();
+    let (exis_files, _, indexed) = index_library(&config, &mut cache, false, &report);
+    let exis_groups =
+        find_duplicate_groups(&exis_files, &config.keeper_policy, config.duplicate_check);
+    let actions = process_files(&exis_files, &exis_groups, &config, None, &indexed, &report);
+    let actions = number_sequences(actions, &indexed);
+
+    progress::suspend(|| report_duplicates(&exis_groups, true, config.dry_run));
+    report.record_duplicates(&exis_groups);
+    let ok = run_actions(&actions, &config, &indexed, &mut cache, &report);
+    return finish_run(&config, &report, ok);
+}
+
+/// Prints the run report and writes it when asked to. Returns whether the run succeeded
+/// without any failed files.
+fn finish_run(config: &Config, report: &RunReport, ok: bool) -> bool {
+    progress::finish_overall();
+    report.print_summary();
+    report.print_errors();
+    if let Some(report_path) = &config.report_out {
+        if let Err(e) = report.write_json(report_path) {
+            eprintln!("error: {}", e);
+            return false;
+        }
+        println!("Report written to {}", report_path.display());
     }
+    return ok && !report.has_errors();
+}
 
-    process_actions(&actions);
+/// Settles the destinations of the planned actions and executes them, unless the run only
+/// prints or exports the plan. The index cache is only saved once the library has actually
+/// been changed.
+fn run_actions(
+    actions: &[(Action, PathBuf, PathBuf)],
+    config: &Config,
+    indexed: &HashMap<PathBuf, IndexEntry>,
+    cache: &mut IndexCache,
+    report: &RunReport,
+) -> bool {
+    let mut hashes: HashMap<PathBuf, u64> = indexed
+        .iter()
+        .filter_map(|(path, entry)| Some((path.clone(), entry.hash?)))
+        .collect();
+    if config.plan_out.is_some() {
+        // Apply checks every source against its full hash, so unique files are hashed now.
+        // Sources that cannot be hashed are left out of the plan.
+        let missing: Vec<(PathBuf, u64)> = actions
+            .par_iter()
+            .filter(|(_, src, _)| !hashes.contains_key(src))
+            .filter_map(|(_, src, _)| match get_file_hash(src) {
+                Ok(hash) => Some((src.clone(), hash)),
+                Err(e) => {
+                    report.record(src, &e);
+                    None
+                }
+            })
+            .collect();
+        hashes.extend(missing);
+    }
+    let actions: Vec<(Action, PathBuf, PathBuf)> = actions
+        .iter()
+        .filter(|(_, src, _)| config.plan_out.is_none() || hashes.contains_key(src))
+        .cloned()
+        .collect();
+    let named_dirs = [&config.doc_dir, &config.image_dir, &config.video_dir].map(PathBuf::from);
+    let actions = resolve_destinations(&actions, &named_dirs, report);
+    if let Some(plan_path) = &config.plan_out {
+        if let Err(e) = write_plan(plan_path, &actions, &hashes) {
+            eprintln!("error: {}", e);
+            return false;
+        }
+        progress::suspend(|| {
+            println!("Wrote {} actions to {}", actions.len(), plan_path.display())
+        });
+        return true;
+    }
+    if config.dry_run {
+        progress::suspend(|| print_plan(&actions, config));
+        return true;
+    }
 
-    println!("Num actions: {}", actions.len());
+    let journal_path = config
+        .journal
+        .clone()
+        .unwrap_or_else(|| config.default_journal_path());
+    let journal = match Journal::create(&journal_path) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    let trash = Trash::new(&config.get_library_root());
+    let completed = process_actions(
+        actions,
+        &hashes,
+        &journal,
+        (!config.permanent_delete).then_some(&trash),
+        &config.transfer_options(),
+        report,
+    );
+    cache.record_completed(&completed, indexed);
+    save_index_cache(cache);
+
+    if journal.is_written() {
+        progress::suspend(|| println!("Journal written to {}", journal.path().display()));
+    }
+    return true;
 }
 
-fn index_files(
-    source_dirs: Vec<PathBuf>,
+/// A failed save only costs a slower next run, so it is not treated as an error.
+pub(crate) fn save_index_cache(cache: &IndexCache) {
+    if let Err(e) = cache.save() {
+        progress::suspend(|| println!("Warning: could not save the library index: {}", e));
+    }
+}
+
+/// Executes an exported plan exactly as it is, but only if every source still exists with the
+/// same content and every destination is free.
+pub fn apply_plan(
+    plan_path: &Path,
+    journal_path: Option<PathBuf>,
+    library_root: Option<PathBuf>,
+    permanent_delete: bool,
+    options: TransferOptions,
+) -> Result<bool, String> {
+    let entries = read_plan(plan_path)?;
+    let trash = library_root.map(|root| Trash::new(&root));
+    if trash.is_none()
+        && !permanent_delete
+        && entries.iter().any(|entry| entry.action == Action::Delete)
+    {
+        return Err(
+            "the plan deletes files: pass --library-root to move them to its trash, \
+             or --permanent-delete"
+                .to_owned(),
+        );
+    }
+    let journal_path = journal_path
+        .unwrap_or_else(|| plan_path.with_extension(format!("journal.{}", unique_journal_name())));
+
+    progress::start_overall(2);
+    let sizes: Vec<u64> = entries.iter().map(|e| file_size(&e.source)).collect();
+    let bar = progress::start_phase(
+        "Verifying plan sources",
+        Some(sizes.iter().sum()),
+        Unit::Bytes,
+    );
+    let mut problems: Vec<String> = entries
+        .par_iter()
+        .zip(&sizes)
+        .filter_map(|(entry, size)| {
+            let problem = verify_source(entry);
+            bar.inc(*size);
+            return problem;
+        })
+        .collect();
+    progress::finish_phase(&bar, "✅ Finished verifying plan sources".to_owned());
+    problems.extend(check_destinations(&entries));
+
+    if !problems.is_empty() {
+        progress::finish_overall();
+        problems.sort();
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        println!(
+            "Plan was not applied, {} entries failed verification",
+            problems.len()
+        );
+        return Ok(false);
+    }
+
+    let hashes: HashMap<PathBuf, u64> = entries
+        .iter()
+        .filter_map(|entry| {
+            Some((
+                entry.source.clone(),
+                u64::from_str_radix(&entry.hash, 16).ok()?,
+            ))
+        })
+        .collect();
+    let actions: Vec<(Action, PathBuf, PathBuf)> = entries
+        .into_iter()
+        .map(|entry| (entry.action, entry.source, entry.destination))
+        .collect();
+    for (act, _, dest) in &actions {
+        if let (Action::Copy | Action::Move, Some(dir)) = (act, dest.parent()) {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+    }
+    let journal = Journal::create(&journal_path)?;
+    let report = RunReport::default();
+    let num_actions = actions.len();
+    process_actions(
+        actions,
+        &hashes,
+        &journal,
+        trash.as_ref().filter(|_| !permanent_delete),
+        &options,
+        &report,
+    );
+    progress::finish_overall();
+
+    println!("Num actions: {}", num_actions);
+    if journal.is_written() {
+        println!("Journal written to {}", journal.path().display());
+    }
+    report.print_errors();
+    return Ok(!report.has_errors());
+}
+
+/// Why copies and moves of a plan cannot go to their destination as planned. A destination
+/// may only be taken by a file that the plan moves away or deletes first, and only by one
+/// entry.
+fn check_destinations(entries: &[PlanEntry]) -> Vec<String> {
+    let mut claims = Claims::default();
+    for entry in entries {
+        match entry.action {
+            Action::Move => {
+                claims.leaving.insert(entry.source.clone());
+                claims
+                    .moved
+                    .insert(entry.source.clone(), entry.destination.clone());
+            }
+            Action::Delete => {
+                claims.leaving.insert(entry.source.clone());
+            }
+            Action::Copy => (),
+        }
+    }
+    let mut problems = Vec::new();
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.action != Action::Delete)
+    {
+        let (src, dest) = (&entry.source, &entry.destination);
+        match claims.occupant(dest, src) {
+            Some(occupant) if claims.reserved.contains_key(dest) => problems.push(format!(
+                "{}: {} is also the destination of {}",
+                src.display(),
+                dest.display(),
+                occupant.display()
+            )),
+            Some(_) => problems.push(format!(
+                "{}: {} already exists",
+                src.display(),
+                dest.display()
+            )),
+            None => {
+                claims.reserved.insert(dest.clone(), src.clone());
+            }
+        }
+    }
+    return problems;
+}
+
+/// Why a plan entry's source can no longer be used, if it can't.
+fn verify_source(entry: &PlanEntry) -> Option<String> {
+    if entry.hash.is_empty() {
+        return Some(format!(
+            "{}: no content hash in the plan, cannot verify it",
+            entry.source.display()
+        ));
+    }
+    if !entry.source.is_file() {
+        return Some(format!(
+            "{}: source no longer exists",
+            entry.source.display()
+        ));
+    }
+    let hash = match get_file_hash(&entry.source) {
+        Ok(hash) => hash,
+        Err(e) => return Some(format!("{}: {}", entry.source.display(), e)),
+    };
+    if entry.hash != format!("{:016x}", hash) {
+        return Some(format!(
+            "{}: content changed since the plan was made",
+            entry.source.display()
+        ));
+    }
+    return None;
+}
+
+fn file_size(path: &Path) -> u64 {
+    return fs::metadata(path).map_or(0, |m| m.len());
+}
+
+pub fn print_index(dirs: Vec<PathBuf>, file_exts: &HashMap<String, FileTypes>) -> bool {
+    let report = RunReport::default();
+    let files = scan_files(dirs, file_exts, true, None, &report);
+    let mut entries: Vec<(u64, &PathBuf)> = files
+        .par_iter()
+        .filter_map(|(path, _)| match get_file_hash(path) {
+            Ok(hash) => Some((hash, path)),
+            Err(e) => {
+                report.record(path, &e);
+                None
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.1.cmp(b.1));
+    for (hash, path) in entries {
+        println!("{:016x}  {}", hash, path.display());
+    }
+    report.print_errors();
+    return !report.has_errors();
+}
+
+pub fn inspect_files(files: &[PathBuf], file_exts: &HashMap<String, FileTypes>) -> bool {
+    let mut all_ok = true;
+    for path in files {
+        if !path.is_file() {
+            eprintln!("{}: not a file", path.display());
+            all_ok = false;
+            continue;
+        }
+        let file_type = path
+            .extension()
+            .and_then(|e| file_exts.get(e.to_ascii_lowercase().to_str()?));
+        let datetime = read_datetime(path);
+        println!("{}", path.display());
+        match file_type {
+            Some(file_type) => println!("  type:     {:?}", file_type),
+            None => println!("  type:     unsupported"),
+        }
+        match datetime {
+            Ok(Some(dt)) => println!("  datetime: {}", dt.format("%Y-%m-%d %H:%M:%S")),
+            Ok(None) => println!("  datetime: none"),
+            Err(e) => {
+                println!("  datetime: error, {}", e);
+                all_ok = false;
+            }
+        }
+        match get_file_hash(path) {
+            Ok(hash) => println!("  hash:     {:016x}", hash),
+            Err(e) => {
+                println!("  hash:     error, {}", e);
+                all_ok = false;
+            }
+        }
+    }
+    return all_ok;
+}
+
+pub fn verify_files(config: Config) -> bool {
+    let report = RunReport::default();
+    progress::start_overall(4);
+    let mut cache = config.load_index_cache();
+    let (exis_files, new_files, _) = index_library(&config, &mut cache, true, &report);
+    save_index_cache(&cache);
+    progress::finish_overall();
+
+    let mut missing: Vec<&PathBuf> = new_files
+        .iter()
+        .filter(|(h, _)| !exis_files.contains_key(h))
+        .flat_map(|(_, paths)| paths)
+        .collect();
+    let num_new_files: usize = new_files.values().map(|paths| paths.len()).sum();
+    missing.sort();
+    for path in &missing {
+        println!("Missing: {}", path.display());
+    }
+
+    println!(
+        "{} of {} source files are in the library",
+        num_new_files - missing.len(),
+        num_new_files
+    );
+    report.print_errors();
+    return missing.is_empty() && !report.has_errors();
+}
+
+/// Indexes the library, and the source folders when `with_sources`. Returns the library
+/// index, the source index and the entry of every indexed file. The cache is replaced by
+/// the library files found. Files that cannot be read are recorded in the report and left out.
+fn index_library(
     config: &Config,
+    cache: &mut IndexCache,
+    with_sources: bool,
+    report: &RunReport,
+) -> (FileIndex, FileIndex, HashMap<PathBuf, IndexEntry>) {
+    let mut files = scan_files(
+        config.get_destination_folders(),
+        &config.file_exts,
+        true,
+        Some(cache),
+        report,
+    );
+    let mut num_existing = files.len();
+    if with_sources {
+        files.append(&mut scan_files(
+            config.get_source_folders(),
+            &config.file_exts,
+            false,
+            None,
+            report,
+        ));
+    }
+    let failed = hash_colliding_files(&mut files, report);
+    num_existing -= files[..num_existing]
+        .iter()
+        .filter(|(path, _)| failed.contains(path))
+        .count();
+    files.retain(|(path, _)| !failed.contains(path));
+
+    let mut exis_files: FileIndex = HashMap::new();
+    let mut new_files: FileIndex = HashMap::new();
+    for (i, (path, entry)) in files.iter().enumerate() {
+        let index = match i < num_existing {
+            true => &mut exis_files,
+            false => &mut new_files,
+        };
+        index
+            .entry(entry.content_key())
+            .or_default()
+            .push(path.clone());
+    }
+    replace_index_entries(cache, &files[..num_existing]);
+    return (exis_files, new_files, files.into_iter().collect());
+}
+
+/// Replaces the cache with the library files found by the latest walk.
+pub(crate) fn replace_index_entries(cache: &mut IndexCache, files: &[(PathBuf, IndexEntry)]) {
+    let pruned = cache.replace_entries(files.iter().cloned().collect());
+    if pruned > 0 {
+        progress::suspend(|| println!("Pruned {} stale entries from the index", pruned));
+    }
+}
+
+/// Finds every supported file in the folders, without reading any content beyond the metadata
+/// needed to locate it. With a cache, files whose size and modification time are unchanged
+/// reuse their cached entry. Files whose content cannot be located are recorded in the report
+/// and left out, files whose datetime cannot be read are kept without one.
+pub(crate) fn scan_files(
+    source_dirs: Vec<PathBuf>,
+    file_exts: &HashMap<String, FileTypes>,
     existing: bool,
-) -> HashMap<u64, PathBuf> {
+    cache: Option<&IndexCache>,
+    report: &RunReport,
+) -> Vec<(PathBuf, IndexEntry)> {
     let message_type = match existing {
         true => "existing",
         false => "new",
     };
-    let bar = ProgressBar::new_spinner();
-    bar.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner} [{elapsed_precise}] {wide_msg}")
-            .unwrap(),
+    let bar = progress::start_phase(
+        &format!("Indexing {} files", message_type),
+        None,
+        Unit::Files,
     );
-    bar.enable_steady_tick(std::time::Duration::from_millis(200));
-    bar.set_message(format!("Indexing {} files", message_type));
 
-    let indexed_files: HashMap<u64, PathBuf> = source_dirs
+    let files: Vec<(PathBuf, IndexEntry)> = source_dirs
         .iter()
         .flat_map(|dir| {
-            let files: HashMap<u64, PathBuf> = WalkDir::new(&dir)
+            let files: Vec<(PathBuf, IndexEntry)> = WalkDir::new(dir)
+                // The trash and other state in `.sorter` are not part of the library
+                .process_read_dir(|_, _, _, children| {
+                    children.retain(|child| {
+                        child.as_ref().map_or(true, |c| c.file_name() != ".sorter")
+                    });
+                })
                 .into_iter()
                 .par_bridge()
-                .filter(|e| e.is_ok())
                 .filter_map(|e| {
-                    let entry = e.unwrap();
-                    if entry.metadata().unwrap().is_dir() {
+                    let entry = match e {
+                        Ok(entry) => entry,
+                        // A destination folder that does not exist yet has no files
+                        Err(e)
+                            if e.depth() == 0
+                                && e.io_error()
+                                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+                        {
+                            return None;
+                        }
+                        Err(e) => {
+                            // A folder that cannot be read, its files are left out
+                            report.record_message(e.path().unwrap_or(dir), e.to_string());
+                            return None;
+                        }
+                    };
+                    if entry.file_type().is_dir() {
+                        if let Some(e) = &entry.read_children_error {
+                            report.record_message(&entry.path(), e.to_string());
+                        }
                         bar.set_message(format!(
                             "Indexing {} files: {}",
                             message_type,
@@ -76,207 +565,1201 @@ fn index_files(
                         ));
                         return None;
                     }
-                    let path = &entry.path();
-                    let ext_result = &path.extension();
-                    if ext_result.is_none()
-                        || !config.file_exts.contains_key(
-                            &ext_result.unwrap().to_ascii_lowercase().to_str().unwrap(),
-                        )
+                    let path = entry.path();
+                    let file_type = match path
+                        .extension()
+                        .and_then(|e| file_exts.get(e.to_ascii_lowercase().to_str()?))
                     {
-                        // println!("Ignored: {}", &path.display());
-                        return None;
+                        Some(file_type) => file_type,
+                        None => {
+                            debug!("{}: ignored, unsupported extension", path.display());
+                            return None;
+                        }
+                    };
+                    let metadata = entry.metadata().map_err(|e| e.to_string());
+                    let (modified, metadata) = match metadata
+                        .and_then(|m| Ok((m.modified().map_err(|e| e.to_string())?, m)))
+                    {
+                        Ok(metadata) => metadata,
+                        Err(e) => {
+                            report.record_message(&path, e);
+                            return None;
+                        }
+                    };
+                    if let Some(hit) = cache.and_then(|c| c.lookup(&path, metadata.len(), modified))
+                    {
+                        trace!("{}: unchanged, using the index entry", path.display());
+                        report.record_scanned(dir, *file_type, hit.datetime.is_some());
+                        if let Some(format) = hit.metadata_format {
+                            report.record_parsed(format, hit.datetime.is_some());
+                        }
+                        return Some((path, hit.clone()));
                     }
-                    return Some((get_file_hash(&entry.path()), entry.path()));
+                    let content_size = match content_size(&path) {
+                        Ok(content_size) => content_size,
+                        Err(e) => {
+                            report.record(&path, &e);
+                            return None;
+                        }
+                    };
+                    let (metadata_format, media_metadata) = match file_type {
+                        FileTypes::DOCUMENT => (None, MediaMetadata::default()),
+                        FileTypes::IMAGE | FileTypes::VIDEO => {
+                            let (format, media_metadata) = read_metadata(&path);
+                            if let Some(format) = format {
+                                let dated =
+                                    matches!(&media_metadata, Ok(m) if m.datetime.is_some());
+                                report.record_parsed(format, dated);
+                            }
+                            let media_metadata = media_metadata.unwrap_or_else(|e| {
+                                report.record(&path, &e);
+                                MediaMetadata::default()
+                            });
+                            (format, media_metadata)
+                        }
+                    };
+                    report.record_scanned(dir, *file_type, media_metadata.datetime.is_some());
+                    let index_entry = IndexEntry {
+                        size: metadata.len(),
+                        modified,
+                        content_size,
+                        partial_hash: None,
+                        hash: None,
+                        perceptual_hash: None,
+                        datetime: media_metadata.datetime,
+                        camera_make: media_metadata.camera_make,
+                        camera_model: media_metadata.camera_model,
+                        metadata_format,
+                    };
+                    return Some((path, index_entry));
                 })
                 .collect();
             return files;
         })
         .collect();
 
-    bar.finish();
-    bar.set_message(format!("✅ Finished indexing {} files", message_type));
-    return indexed_files;
+    progress::finish_phase(&bar, format!("✅ Finished indexing {} files", message_type));
+    return files;
 }
 
-fn get_file_hash(path: &Path) -> u64 {
-    // TODO: this should call parsers to work with exif, riff and quicktime
-    let mut reader = StepableBuffReader::new(File::open(&path).unwrap());
+/// Computes partial hashes for files sharing their content size with another file, then full
+/// hashes for files also sharing the partial hash. Other files are never read in full.
+/// Returns the files that could not be hashed.
+fn hash_colliding_files(
+    files: &mut [(PathBuf, IndexEntry)],
+    report: &RunReport,
+) -> HashSet<PathBuf> {
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    for (_, entry) in files.iter() {
+        *sizes.entry(entry.content_size).or_default() += 1;
+    }
+    let mut failed = hash_files(
+        files,
+        "Partially hashing files with matching sizes",
+        report,
+        |_, entry| entry.partial_hash.is_none() && sizes[&entry.content_size] > 1,
+        |entry| partial_hash_len(entry.content_size),
+        |path, entry| {
+            entry.partial_hash = Some(partial_hash(path)?);
+            return Ok(());
+        },
+    );
 
-    let exif_tags = vec![vec![0xFF, 0xD8, 0xFF, 0xE1], vec![0xFF, 0xD8, 0xFF, 0xE0]];
-    if reader.compare_multiple_bytes(exif_tags) {
-        while reader.increment() {
-            if reader.compare_bytes(vec![0xFF, 0xDA]) {
-                return xxh3_64(&reader.read_to_end());
-            }
+    let mut partial_hashes: HashMap<(u64, u64), usize> = HashMap::new();
+    for (_, entry) in files.iter() {
+        if let Some(partial_hash) = entry.partial_hash {
+            *partial_hashes
+                .entry((entry.content_size, partial_hash))
+                .or_default() += 1;
         }
-        panic!("File did not have FF DA");
     }
+    failed.extend(hash_files(
+        files,
+        "Hashing files with matching partial hashes",
+        report,
+        |_, entry| {
+            entry.hash.is_none()
+                && entry
+                    .partial_hash
+                    .is_some_and(|p| partial_hashes[&(entry.content_size, p)] > 1)
+        },
+        |entry| entry.content_size,
+        |path, entry| {
+            entry.hash = Some(get_file_hash(path)?);
+            return Ok(());
+        },
+    ));
+    return failed;
+}
 
-    // println!("File without EXIf: {:?} {}", reader.peak(8), path.display());
-    return xxh3_64(&reader.read_to_end());
+/// Runs `hash` on every file for which `needs_hash` holds, in parallel with a progress bar
+/// counting the `bytes_read` of every file. Failures are recorded in the report and the failed
+/// files returned.
+pub(crate) fn hash_files<F, B, H>(
+    files: &mut [(PathBuf, IndexEntry)],
+    message: &str,
+    report: &RunReport,
+    needs_hash: F,
+    bytes_read: B,
+    hash: H,
+) -> HashSet<PathBuf>
+where
+    F: Fn(&Path, &IndexEntry) -> bool + Sync,
+    B: Fn(&IndexEntry) -> u64 + Sync,
+    H: Fn(&Path, &mut IndexEntry) -> FileResult<()> + Sync,
+{
+    let total = files
+        .iter()
+        .filter(|(p, e)| needs_hash(p, e))
+        .map(|(_, e)| bytes_read(e))
+        .sum();
+    let bar = progress::start_phase(message, Some(total), Unit::Bytes);
+    let failed = files
+        .par_iter_mut()
+        .filter(|(path, entry)| needs_hash(path, entry))
+        .filter_map(|(path, entry)| {
+            let size = bytes_read(entry);
+            let result = hash(path, entry);
+            bar.inc(size);
+            report.record(path, &result.err()?);
+            return Some(path.clone());
+        })
+        .collect();
+
+    progress::finish_phase(&bar, format!("✅ Finished {}", message.to_lowercase()));
+    return failed;
 }
 
 fn process_files(
-    files: &HashMap<u64, PathBuf>,
+    files: &FileIndex,
+    duplicate_groups: &[DuplicateGroup],
     config: &Config,
-    existing_hashes: Option<&HashMap<u64, PathBuf>>,
+    existing_hashes: Option<&FileIndex>,
+    indexed: &HashMap<PathBuf, IndexEntry>,
+    report: &RunReport,
 ) -> Vec<(Action, PathBuf, PathBuf)> {
     let new_files = existing_hashes.is_some();
     let file_type_msg = match new_files {
         true => "new",
         false => "existing",
     };
-    let bar = ProgressBar::new_spinner();
-    bar.set_style(
-        ProgressStyle::default_spinner()
-            .template(
-                "{spinner} [{elapsed_precise}] {wide_msg} [{bar:20}] ({pos}/{len}, ETA {eta})",
-            )
-            .unwrap(),
+    let bar = progress::start_phase(
+        &format!("  Processing {} files", file_type_msg),
+        Some(files.values().map(|paths| paths.len() as u64).sum()),
+        Unit::Files,
     );
-    bar.set_message(format!("  Processing {} files", file_type_msg));
-    bar.set_length(files.len() as u64);
 
-    let file_lookup: HashMap<PathBuf, u64> =
-        files.iter().map(|(h, p)| (p.clone(), h.clone())).collect();
+    // Only the keeper of every duplicate group stays, the rest are deleted from the
+    // library or skipped when importing
+    let duplicates: HashSet<&PathBuf> = duplicate_groups
+        .iter()
+        .flat_map(|group| &group.duplicates)
+        .collect();
+    for group in duplicate_groups {
+        for duplicate in &group.duplicates {
+            debug!(
+                "{}: duplicate of {}, which is kept",
+                duplicate.display(),
+                group.keeper.display()
+            );
+        }
+    }
     let actions: Vec<(Action, PathBuf, PathBuf)> = files
         .par_iter()
+        .flat_map_iter(|(h, paths)| paths.iter().map(move |p| (h, p)))
         .progress_with(bar.clone())
         .filter_map(|(h, p)| {
-            if new_files && existing_hashes.unwrap().contains_key(h) {
+            if new_files && already_in_library(p, existing_hashes.unwrap().get(h), config) {
+                debug!("{}: skipped, content already in the library", p.display());
                 return None;
             }
+            if duplicates.contains(p) {
+                return match new_files {
+                    true => {
+                        debug!("{}: skipped, duplicate of another new file", p.display());
+                        None
+                    }
+                    false => Some((Action::Delete, p.clone(), p.clone())),
+                };
+            }
             let ext_option = p.extension().unwrap().to_ascii_lowercase();
             let ext = ext_option.to_str().unwrap();
             let result = match config.file_exts.get(ext).unwrap() {
                 FileTypes::IMAGE => {
-                    process_media(&config, MediaType::IMAGE, &p, ext, &file_lookup, new_files)
+                    process_media(config, MediaType::IMAGE, p, ext, new_files, indexed, report)
                 }
                 FileTypes::VIDEO => {
-                    process_media(&config, MediaType::VIDEO, &p, ext, &file_lookup, new_files)
+                    process_media(config, MediaType::VIDEO, p, ext, new_files, indexed, report)
                 }
-                FileTypes::DOCUMENT => process_document(&config, &p, &file_lookup, new_files),
+                FileTypes::DOCUMENT => process_document(config, p, new_files),
             };
             return result;
         })
         .collect();
 
-    bar.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner} [{elapsed_precise}] {wide_msg}")
-            .unwrap(),
+    progress::finish_phase(
+        &bar,
+        format!("✅ Finished processing {} files", file_type_msg),
     );
-    bar.finish();
-    bar.set_message(format!("✅ Finished processing {} files", file_type_msg));
     return actions;
 }
 
+/// Fills in the `{seq}` of new names, once the actions of library and new files are known.
+/// Library files already numbered for their name keep their number. The others continue
+/// after the highest number in the destination folder, in order of datetime and then path,
+/// so the numbers do not depend on the order the files were processed in.
+fn number_sequences(
+    actions: Vec<(Action, PathBuf, PathBuf)>,
+    indexed: &HashMap<PathBuf, IndexEntry>,
+) -> Vec<(Action, PathBuf, PathBuf)> {
+    let (numbered, mut actions): (Vec<_>, Vec<_>) = actions
+        .into_iter()
+        .partition(|(_, _, dest)| has_sequence(&dest.to_string_lossy()));
+    let mut groups: HashMap<PathBuf, Vec<(Action, PathBuf)>> = HashMap::new();
+    for (action, src, dest) in numbered {
+        groups.entry(dest).or_default().push((action, src));
+    }
+    for (dest, mut files) in groups {
+        let name = dest.file_name().unwrap().to_string_lossy().into_owned();
+        let dir = dest.parent().unwrap_or(Path::new(""));
+        let number = |path: &Path| sequence_number(&name, path.file_name()?.to_str()?);
+        files.retain(|(_, src)| src.parent() != Some(dir) || number(src).is_none());
+        let highest = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| number(&entry.ok()?.path()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+        files.sort_by_key(|(_, src)| (indexed.get(src).and_then(|e| e.datetime), src.clone()));
+        for (seq, (action, src)) in (highest + 1..).zip(files) {
+            actions.push((action, src, dest.with_file_name(fill_sequence(&name, seq))));
+        }
+    }
+    return actions;
+}
+
+/// Whether the content of a new file is already in the library, confirming the hash match
+/// when skips are confirmed.
+fn already_in_library(path: &Path, existing: Option<&Vec<PathBuf>>, config: &Config) -> bool {
+    return existing.is_some_and(|existing| {
+        existing
+            .iter()
+            .any(|e| files_identical(e, path, config.skip_check()))
+    });
+}
+
 fn process_media(
     config: &Config,
     media_type: MediaType,
-    path: &PathBuf,
+    path: &Path,
     ext: &str,
-    file_lookup: &HashMap<PathBuf, u64>,
     new_files: bool,
+    indexed: &HashMap<PathBuf, IndexEntry>,
+    report: &RunReport,
 ) -> Option<(Action, PathBuf, PathBuf)> {
-    if file_lookup.get(path).is_none() {
-        if !new_files {
-            return Some((Action::Delete, path.clone(), path.clone()));
-        }
-        return None;
-    }
-    let dt = read_datetime(&path);
-    if dt.is_none() {
-        // println!("OOPS! No datetime for {}", path.display()) // TODO: temporary
-    }
+    let metadata = match indexed.get(path) {
+        Some(entry) => MediaMetadata {
+            datetime: entry.datetime,
+            camera_make: entry.camera_make.clone(),
+            camera_model: entry.camera_model.clone(),
+            ..Default::default()
+        },
+        None => read_metadata(path).1.unwrap_or_else(|e| {
+            report.record(path, &e);
+            MediaMetadata::default()
+        }),
+    };
+    let dt = metadata.datetime;
     let file_prefix = match media_type {
         MediaType::IMAGE => "IMG",
         MediaType::VIDEO => "VID",
     };
+    let hash = match dt.is_some() && uses_field(&config.name_template, "hash") {
+        true => match indexed.get(path).and_then(|entry| entry.hash) {
+            Some(hash) => Some(hash),
+            None => match get_file_hash(path) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    report.record(path, &e);
+                    return None;
+                }
+            },
+        },
+        false => None,
+    };
+    let values = TemplateValues {
+        datetime: dt,
+        camera_make: metadata.camera_make.as_deref(),
+        camera_model: metadata.camera_model.as_deref(),
+        stem: path.file_stem().and_then(|stem| stem.to_str()),
+        media_type: Some(match media_type {
+            MediaType::IMAGE => "image",
+            MediaType::VIDEO => "video",
+        }),
+        prefix: Some(file_prefix),
+        hash,
+    };
+    // The stem of a library file is already a rendered name, using it again would repeat it
+    let keeps_name = !new_files && uses_field(&config.name_template, "stem");
     let file_name = match dt {
-        Some(dt) => PathBuf::from(format!(
-            "{}_{}.{}",
-            file_prefix,
-            dt.format("%Y%m%d_%H%M%S"),
-            ext
-        )),
-        None => PathBuf::from(path.file_name().unwrap()),
+        Some(_) if !keeps_name => {
+            let name = render_name(&config.name_template, &values, ext);
+            if let Err(e) = check_file_name(&fill_sequence(&name, 1)) {
+                report.record_message(path, format!("new name `{}` {}", name, e));
+                return None;
+            }
+            PathBuf::from(name)
+        }
+        _ => PathBuf::from(path.file_name().unwrap()),
     };
+    // Undated files stay together in the temp folder until they are dated by hand
+    let layout = render_layout(&config.layout, &values);
     let dest_dir = match media_type {
-        MediaType::IMAGE if dt.is_some() => PathBuf::from(config.image_dir.clone()).join(file_name),
+        MediaType::IMAGE if dt.is_some() => PathBuf::from(config.image_dir.clone())
+            .join(layout)
+            .join(file_name),
         MediaType::IMAGE => PathBuf::from(config.image_dir.clone() + "_temp").join(file_name),
-        MediaType::VIDEO if dt.is_some() => PathBuf::from(config.video_dir.clone()).join(file_name),
+        MediaType::VIDEO if dt.is_some() => PathBuf::from(config.video_dir.clone())
+            .join(layout)
+            .join(file_name),
         MediaType::VIDEO => PathBuf::from(config.video_dir.clone() + "_temp").join(file_name),
     };
-    return match &dest_dir != path {
-        true if new_files => Some((Action::Copy, path.clone(), dest_dir)),
-        true => Some((Action::Move, path.clone(), dest_dir)),
+    match dt {
+        Some(dt) => debug!(
+            "{}: dated {}, goes to {}",
+            path.display(),
+            dt,
+            dest_dir.display()
+        ),
+        None => debug!(
+            "{}: no datetime in its metadata, goes to {}",
+            path.display(),
+            dest_dir.display()
+        ),
+    }
+    return match dest_dir != path {
+        true if new_files => Some((import_action(config), path.to_path_buf(), dest_dir)),
+        true => Some((Action::Move, path.to_path_buf(), dest_dir)),
         false => None,
     };
 }
 
 fn process_document(
     config: &Config,
-    path: &PathBuf,
-    file_lookup: &HashMap<PathBuf, u64>,
+    path: &Path,
     new_files: bool,
 ) -> Option<(Action, PathBuf, PathBuf)> {
     if !new_files {
+        trace!("{}: documents in the library stay in place", path.display());
         return None;
     }
-    let dest_path = PathBuf::from(config.doc_dir.clone()).join(path.file_name()?);
-    return Some((Action::Copy, path.clone(), dest_path));
+    let layout = render_layout(
+        &config.layout,
+        &TemplateValues {
+            datetime: document_datetime(path),
+            ..Default::default()
+        },
+    );
+    let dest_path = PathBuf::from(config.doc_dir.clone())
+        .join(layout)
+        .join(path.file_name()?);
+    return Some((import_action(config), path.to_path_buf(), dest_path));
 }
 
-fn process_actions(actions: &Vec<(Action, PathBuf, PathBuf)>) {
-    let bar = ProgressBar::new_spinner();
-    bar.set_style(
-        ProgressStyle::default_spinner()
-            .template(
-                "{spinner} [{elapsed_precise}] {wide_msg} [{bar:20}] ({pos}/{len}, ETA {eta})",
-            )
-            .unwrap(),
+/// Documents have no metadata datetime, their modification time stands in for it. Like the
+/// camera time of media, it is the local time, so both land in the same day's folder.
+fn document_datetime(path: &Path) -> Option<DateTime<Utc>> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    return Some(DateTime::<Local>::from(modified).naive_local().and_utc());
+}
+
+fn import_action(config: &Config) -> Action {
+    return match config.import_mode {
+        ImportMode::Copy => Action::Copy,
+        ImportMode::Move => Action::Move,
+    };
+}
+
+/// Executes actions with settled destinations and returns the completed ones with their final
+/// destinations and content hashes. Files that were never fully hashed are hashed once they
+/// are in place. When verifying copies, they are compared with their source byte for byte
+/// before they are put in place. Failed actions are recorded in the report. Once the journal
+/// cannot be written, no further file is touched, as the run could no longer be undone.
+fn process_actions(
+    actions: Vec<(Action, PathBuf, PathBuf)>,
+    hashes: &HashMap<PathBuf, u64>,
+    journal: &Journal,
+    trash: Option<&Trash>,
+    options: &TransferOptions,
+    report: &RunReport,
+) -> Vec<(Action, PathBuf, PathBuf, Option<u64>)> {
+    let actions = trash_destinations(actions, trash, report);
+    let bar = progress::start_phase(
+        "Processing file changes",
+        Some(actions.iter().map(|(_, src, _)| file_size(src)).sum()),
+        Unit::Bytes,
     );
-    bar.set_message("Processing file changes");
-    bar.set_length(actions.len() as u64);
-    actions
-        .par_iter()
-        .progress_with(bar.clone())
-        .for_each(|(act, src, dest)| match act {
-            Action::Copy => copy_file(src, dest, true),
-            Action::Move => copy_file(src, dest, false),
-            Action::Delete => delete_file(src),
+    let journal_failed = AtomicBool::new(false);
+    let execute = |(act, src, dest): (Action, PathBuf, PathBuf)| {
+        if journal_failed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let size = file_size(&src);
+        let modified = match options.mtime_from_metadata {
+            true => read_capture_time(&src).ok().flatten(),
+            false => None,
+        };
+        let preserve = &options.preserve;
+        let result = match act {
+            Action::Copy => copy_file(&src, &dest, options.verify_copies, preserve, modified),
+            Action::Move => move_file(&src, &dest, preserve, modified),
+            Action::Delete => delete_file(&src, &dest, trash),
+        };
+        bar.inc(size);
+        if let Err(e) = result {
+            report.record_message(&src, e.to_string());
+            return None;
+        }
+        report.record_action(act, size);
+        info!("{}: {:?} to {}", src.display(), act, dest.display());
+        let hash = hashes.get(&src).copied().or_else(|| match act {
+            Action::Copy | Action::Move => get_file_hash(&dest)
+                .map_err(|e| report.record(&dest, &e))
+                .ok(),
+            Action::Delete => None,
         });
+        if let Err(e) = journal.record(act, &src, &dest, hash) {
+            if !journal_failed.swap(true, Ordering::Relaxed) {
+                report.record(journal.path(), &e);
+            }
+        }
+        return Some((act, src, dest, hash));
+    };
+    let mut completed = Vec::new();
+    for stage in execution_stages(actions) {
+        completed.par_extend(stage.into_par_iter().filter_map(execute));
+    }
+    if journal_failed.load(Ordering::Relaxed) {
+        progress::suspend(|| {
+            eprintln!(
+                "error: stopped after writing {} failed, the remaining actions were not run",
+                journal.path().display()
+            )
+        });
+    }
 
-    bar.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner} [{elapsed_precise}] {wide_msg}")
-            .unwrap(),
-    );
-    bar.finish();
-    bar.set_message("✅ Finished processing file changes");
-}
-
-fn copy_file(src: &PathBuf, dest: &PathBuf, copy: bool) {
-    let mut new_path = dest.clone();
-    let mut count = 1;
-    while new_path.exists() {
-        let new_file_name = format!("{}({})", dest.file_stem().unwrap().to_str().unwrap(), count);
-        let new_file_name_with_ext = format!(
-            "{}.{}",
-            new_file_name,
-            dest.extension().unwrap().to_str().unwrap()
-        );
-        new_path.set_file_name(new_file_name_with_ext);
-        count += 1;
+    progress::finish_phase(&bar, "✅ Finished processing file changes".to_owned());
+    return completed;
+}
+
+/// Splits the actions into stages that run one after the other. A file only goes to a path
+/// in the stage after the file leaving that path has gone, everything else runs in parallel.
+fn execution_stages(
+    actions: Vec<(Action, PathBuf, PathBuf)>,
+) -> Vec<Vec<(Action, PathBuf, PathBuf)>> {
+    let leaving: HashMap<&PathBuf, usize> = actions
+        .iter()
+        .enumerate()
+        .filter(|(_, (act, _, _))| *act != Action::Copy)
+        .map(|(i, (_, src, _))| (src, i))
+        .collect();
+    let stages: Vec<usize> = (0..actions.len())
+        .map(|i| {
+            // Resolving the destinations ruled out circles, the bound is only a safeguard
+            let mut stage = 0;
+            let mut current = i;
+            while let Some(&next) = leaving.get(&actions[current].2) {
+                if next == current || stage == actions.len() {
+                    break;
+                }
+                stage += 1;
+                current = next;
+            }
+            return stage;
+        })
+        .collect();
+    let mut grouped = vec![Vec::new(); stages.iter().max().map_or(0, |max| max + 1)];
+    for (action, stage) in actions.into_iter().zip(stages) {
+        grouped[stage].push(action);
+    }
+    return grouped;
+}
+
+/// Where a copied or moved file is going.
+enum Placement {
+    Placed(PathBuf),
+    /// A byte-identical file already is at this path.
+    Identical(PathBuf),
+}
+
+/// What is known about the paths while destinations are settled.
+#[derive(Default)]
+struct Claims {
+    /// Reserved destination to the file going there.
+    reserved: HashMap<PathBuf, PathBuf>,
+    /// Sources of moves and deletes, their paths are free once they are done.
+    leaving: HashSet<PathBuf>,
+    /// Where leaving files were settled to go so far.
+    moved: HashMap<PathBuf, PathBuf>,
+}
+
+impl Claims {
+    /// The file that has or will get the path. A file leaving the path does not count, unless
+    /// it is going to `src`'s path, directly or by way of other files, which would never end.
+    fn occupant(&self, path: &Path, src: &Path) -> Option<PathBuf> {
+        if let Some(occupant) = self.reserved.get(path) {
+            return Some(occupant.clone());
+        }
+        if fs::symlink_metadata(path).is_err() {
+            return None;
+        }
+        return match path == src || !self.leaving.contains(path) || self.leads_to(path, src) {
+            true => Some(path.to_path_buf()),
+            false => None,
+        };
+    }
+
+    fn leads_to(&self, from: &Path, to: &Path) -> bool {
+        let mut path = from;
+        for _ in 0..=self.moved.len() {
+            match self.moved.get(path) {
+                Some(next) if next == to => return true,
+                Some(next) => path = next,
+                None => return false,
+            }
+        }
+        return true;
+    }
+}
+
+/// Settles the final destination of every copy and move before anything is printed, exported
+/// or executed, so no two actions can claim the same name. Destinations are reserved in order
+/// of destination and source, which keeps the outcome independent of how the actions are
+/// later scheduled. The path of a file that is moved away or deleted is free, `execution_stages` moves
+/// that file out first. Copies and moves whose destination already holds identical content
+/// are left out, and as their files then stay, destinations are settled again until every
+/// path taken to be free is.
+fn resolve_destinations(
+    actions: &[(Action, PathBuf, PathBuf)],
+    named_dirs: &[PathBuf],
+    report: &RunReport,
+) -> Vec<(Action, PathBuf, PathBuf)> {
+    let mut ordered: Vec<&(Action, PathBuf, PathBuf)> = actions.iter().collect();
+    ordered.sort_by(|(_, src_a, dest_a), (_, src_b, dest_b)| (dest_a, src_a).cmp(&(dest_b, src_b)));
+    let mut leaving = leaving_sources(ordered.iter().copied());
+    loop {
+        let mut claims = Claims {
+            leaving,
+            ..Default::default()
+        };
+        let (resolved, skipped, failed) = settle_destinations(&ordered, named_dirs, &mut claims);
+        let left = leaving_sources(resolved.iter());
+        if claims.leaving.is_subset(&left) {
+            for (src, existing) in skipped {
+                info!(
+                    "{}: skipped, identical to {}",
+                    src.display(),
+                    existing.display()
+                );
+                report.record_skipped();
+            }
+            for (src, e) in failed {
+                report.record_message(&src, e.to_string());
+            }
+            return resolved;
+        }
+        // Shrinks every round, so this ends at the latest when no path is taken to be free
+        leaving = claims.leaving.intersection(&left).cloned().collect();
+    }
+}
+
+/// The paths moves and deletes take their files away from.
+fn leaving_sources<'a>(
+    actions: impl Iterator<Item = &'a (Action, PathBuf, PathBuf)>,
+) -> HashSet<PathBuf> {
+    return actions
+        .filter(|(act, _, _)| *act != Action::Copy)
+        .map(|(_, src, _)| src.clone())
+        .collect();
+}
+
+/// One round of `resolve_destinations`, returning the placed actions, the skipped files with
+/// the identical file they were skipped for, and the files that could not be placed.
+#[allow(clippy::type_complexity)]
+fn settle_destinations(
+    ordered: &[&(Action, PathBuf, PathBuf)],
+    named_dirs: &[PathBuf],
+    claims: &mut Claims,
+) -> (
+    Vec<(Action, PathBuf, PathBuf)>,
+    Vec<(PathBuf, PathBuf)>,
+    Vec<(PathBuf, io::Error)>,
+) {
+    let mut resolved = Vec::with_capacity(ordered.len());
+    let mut skipped = Vec::new();
+    let mut failed = Vec::new();
+    for (act, src, dest) in ordered {
+        if *act == Action::Delete {
+            resolved.push((*act, src.clone(), dest.clone()));
+            continue;
+        }
+        let original_name = !named_dirs.iter().any(|dir| src.starts_with(dir));
+        let dest = match free_destination(src, dest, claims, original_name) {
+            Ok(Placement::Placed(dest)) => dest,
+            Ok(Placement::Identical(existing)) => {
+                if existing != *src {
+                    skipped.push((src.clone(), existing));
+                }
+                continue;
+            }
+            Err(e) => {
+                failed.push((src.clone(), e));
+                continue;
+            }
+        };
+        claims.reserved.insert(dest.clone(), src.clone());
+        if *act == Action::Move {
+            claims.moved.insert(src.clone(), dest.clone());
+        }
+        resolved.push((*act, src.clone(), dest));
+    }
+    return (resolved, skipped, failed);
+}
+
+/// Gives every delete its path in the trash, or keeps it a permanent delete without a trash.
+/// Files deleted from the same path on the same day are disambiguated like other collisions.
+fn trash_destinations(
+    actions: Vec<(Action, PathBuf, PathBuf)>,
+    trash: Option<&Trash>,
+    report: &RunReport,
+) -> Vec<(Action, PathBuf, PathBuf)> {
+    let trash = match trash {
+        Some(trash) => trash,
+        None => return actions,
+    };
+    let mut claims = Claims::default();
+    let mut resolved = Vec::with_capacity(actions.len());
+    for (act, src, dest) in actions {
+        if act != Action::Delete {
+            resolved.push((act, src, dest));
+            continue;
+        }
+        let dest = match free_destination(&src, &trash.trash_path(&src), &claims, false) {
+            // Still deleted, the trash already holds the same file from earlier today
+            Ok(Placement::Placed(dest) | Placement::Identical(dest)) => dest,
+            Err(e) => {
+                report.record_message(&src, e.to_string());
+                continue;
+            }
+        };
+        claims.reserved.insert(dest.clone(), src.clone());
+        resolved.push((act, src, dest));
+    }
+    return resolved;
+}
+
+/// The destination, or when a different file has or will get its name, the first free name
+/// with a disambiguator: the sub-seconds of the datetime, the number the camera gave the file,
+/// or a short content hash. These only depend on the file, so it gets the same name whichever
+/// file claimed the destination first. The camera number is only taken from an original name,
+/// a library file's name was rendered by the name template, and a library file keeps the
+/// disambiguator it already has. Stops at a byte-identical file.
+fn free_destination(
+    src: &Path,
+    dest: &Path,
+    claims: &Claims,
+    original_name: bool,
+) -> io::Result<Placement> {
+    let hash = || match get_file_hash(src) {
+        Ok(hash) => Ok(Some(format!("{:016x}", hash))),
+        Err(e) => Err(io::Error::other(e.to_string())),
+    };
+    let disambiguators: [&dyn Fn() -> io::Result<Option<String>>; 5] = [
+        &|| Ok(current_suffix(src, dest).filter(|_| !original_name)),
+        &|| Ok(sub_seconds(src)),
+        &|| Ok(camera_number(src, dest).filter(|_| original_name)),
+        &|| Ok(hash()?.map(|hash| hash[..8].to_owned())),
+        &hash,
+    ];
+    let mut disambiguators = disambiguators.iter();
+    let mut candidate = dest.to_path_buf();
+    loop {
+        let occupant = match claims.occupant(&candidate, src) {
+            Some(occupant) => occupant,
+            None => return Ok(Placement::Placed(candidate)),
+        };
+        if occupant == src || files_byte_identical(src, &occupant).unwrap_or(false) {
+            return Ok(Placement::Identical(candidate));
+        }
+        let suffix = match disambiguators.find_map(|disambiguator| disambiguator().transpose()) {
+            Some(suffix) => suffix?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} and its disambiguated names are taken", dest.display()),
+                ))
+            }
+        };
+        candidate = with_suffix(dest, &suffix);
+    }
+}
+
+/// `name.ext` becomes `name_suffix.ext`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    return match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}_{}.{}", stem, suffix, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}_{}", stem, suffix)),
+    };
+}
+
+/// The disambiguator of a file already named like the destination, `250` in `name_250.ext`.
+fn current_suffix(src: &Path, dest: &Path) -> Option<String> {
+    if src.parent() != dest.parent() || src.extension() != dest.extension() {
+        return None;
     }
-    if copy {
-        fs::copy(src, new_path).unwrap();
-    } else {
-        fs::rename(src, new_path).unwrap();
+    let stem = src.file_stem()?.to_str()?;
+    let suffix = stem
+        .strip_prefix(dest.file_stem()?.to_str()?)?
+        .strip_prefix('_')?;
+    return match suffix.is_empty() {
+        true => None,
+        false => Some(suffix.to_owned()),
+    };
+}
+
+/// The sub-seconds of the metadata datetime, for shots taken within the same second.
+fn sub_seconds(path: &Path) -> Option<String> {
+    let nanos = read_datetime(path).ok()??.nanosecond();
+    if nanos == 0 {
+        return None;
     }
+    let digits = format!("{:09}", nanos);
+    return Some(format!("{:0<3}", digits.trim_end_matches('0')));
 }
 
-fn delete_file(src: &PathBuf) {
-    todo!()
+/// The number at the end of the original name, like 0123 in `DSC_0123.jpg`, unless the
+/// destination name already ends with it.
+fn camera_number(src: &Path, dest: &Path) -> Option<String> {
+    let stem = src.file_stem()?.to_str()?;
+    let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let number = &stem[stem.len() - digits..];
+    let dest_stem = dest.file_stem()?.to_str()?;
+    return match digits >= 3 && !dest_stem.ends_with(number) {
+        true => Some(number.to_owned()),
+        false => None,
+    };
+}
+
+/// Moves the file into the trash at its resolved destination, or removes it for good when
+/// there is no trash.
+fn delete_file(src: &Path, dest: &Path, trash: Option<&Trash>) -> io::Result<()> {
+    if trash.is_none() {
+        return fs::remove_file(src);
+    }
+    return match move_file(src, dest, Preserve::ALL, None) {
+        // An identical file deleted from the same path earlier today is already in the trash
+        Err(e)
+            if e.kind() == io::ErrorKind::AlreadyExists
+                && files_byte_identical(src, dest).unwrap_or(false) =>
+        {
+            fs::remove_file(src)
+        }
+        result => result.map(|_| ()),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::read_journal;
+
+    fn write(path: &Path, content: &str) -> PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        return path.to_path_buf();
+    }
+
+    fn placed(placement: io::Result<Placement>) -> PathBuf {
+        return match placement.unwrap() {
+            Placement::Placed(path) => path,
+            Placement::Identical(path) => panic!("identical to {}", path.display()),
+        };
+    }
+
+    #[test]
+    fn free_destination_keeps_a_free_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("in/DSC_0123.jpg"), "new");
+        let dest = dir.path().join("lib/IMG.jpg");
+        let placement = free_destination(&src, &dest, &Claims::default(), true);
+        assert_eq!(placed(placement), dest);
+    }
+
+    #[test]
+    fn free_destination_disambiguates_taken_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("in/DSC_0123.jpg"), "new");
+        let dest = write(&dir.path().join("lib/IMG.jpg"), "old");
+        let claims = Claims::default();
+        assert_eq!(
+            placed(free_destination(&src, &dest, &claims, true)),
+            dir.path().join("lib/IMG_0123.jpg")
+        );
+        // A name rendered by the template has no camera number
+        let hash = format!("{:016x}", get_file_hash(&src).unwrap());
+        assert_eq!(
+            placed(free_destination(&src, &dest, &claims, false)),
+            dir.path().join(format!("lib/IMG_{}.jpg", &hash[..8]))
+        );
+    }
+
+    #[test]
+    fn free_destination_stops_at_identical_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("in/DSC_0123.jpg"), "same");
+        let dest = write(&dir.path().join("lib/IMG.jpg"), "same");
+        let placement = free_destination(&src, &dest, &Claims::default(), true);
+        assert!(matches!(placement, Ok(Placement::Identical(path)) if path == dest));
+    }
+
+    #[test]
+    fn free_destination_avoids_reserved_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("in/DSC_0123.jpg"), "new");
+        let other = write(&dir.path().join("in/DSC_0456.jpg"), "other");
+        let dest = dir.path().join("lib/IMG.jpg");
+        let mut claims = Claims::default();
+        claims.reserved.insert(dest.clone(), other);
+        assert_eq!(
+            placed(free_destination(&src, &dest, &claims, true)),
+            dir.path().join("lib/IMG_0123.jpg")
+        );
+    }
+
+    #[test]
+    fn free_destination_takes_over_vacated_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("lib/b.jpg"), "b");
+        let dest = write(&dir.path().join("lib/a.jpg"), "a");
+        let mut claims = Claims::default();
+        claims.leaving.insert(dest.clone());
+        assert_eq!(placed(free_destination(&src, &dest, &claims, false)), dest);
+
+        // Swapping names would need both files to leave first
+        claims.moved.insert(dest.clone(), src.clone());
+        assert_ne!(placed(free_destination(&src, &dest, &claims, false)), dest);
+    }
+
+    #[test]
+    fn free_destination_keeps_the_disambiguator_of_library_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("lib/IMG_0124.jpg"), "second");
+        let dest = write(&dir.path().join("lib/IMG.jpg"), "first");
+        let placement = free_destination(&src, &dest, &Claims::default(), false);
+        assert!(matches!(placement, Ok(Placement::Identical(path)) if path == src));
+    }
+
+    #[test]
+    fn resolve_destinations_gives_colliding_files_their_own_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write(&dir.path().join("in/DSC_0001.jpg"), "first");
+        let second = write(&dir.path().join("in/DSC_0002.jpg"), "second");
+        let deleted = write(&dir.path().join("lib/old.jpg"), "old");
+        let renamed = write(&dir.path().join("lib/x.jpg"), "x");
+        let dest = dir.path().join("lib/IMG.jpg");
+        let actions = [
+            (Action::Copy, second.clone(), dest.clone()),
+            (Action::Copy, first.clone(), dest.clone()),
+            (Action::Delete, deleted.clone(), deleted.clone()),
+            (Action::Move, renamed.clone(), deleted.clone()),
+        ];
+        let named_dirs = [dir.path().join("lib")];
+        let resolved = resolve_destinations(&actions, &named_dirs, &RunReport::default());
+        let dests: Vec<(&PathBuf, &PathBuf)> =
+            resolved.iter().map(|(_, src, dest)| (src, dest)).collect();
+        // The renamed file takes over the name of the deleted one
+        assert_eq!(
+            dests,
+            [
+                (&first, &dest),
+                (&second, &dir.path().join("lib/IMG_0002.jpg")),
+                (&deleted, &deleted),
+                (&renamed, &deleted),
+            ]
+        );
+    }
+
+    fn plan(rows: &[(Action, &Path, &Path)]) -> Vec<PlanEntry> {
+        return rows
+            .iter()
+            .map(|(action, source, destination)| PlanEntry {
+                action: *action,
+                source: source.to_path_buf(),
+                destination: destination.to_path_buf(),
+                hash: format!("{:016x}", get_file_hash(source).unwrap()),
+            })
+            .collect();
+    }
+
+    fn apply(dir: &Path, entries: &[PlanEntry]) -> bool {
+        let plan_path = dir.join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(entries).unwrap()).unwrap();
+        let options = TransferOptions {
+            verify_copies: false,
+            preserve: Vec::new(),
+            mtime_from_metadata: false,
+        };
+        return apply_plan(
+            &plan_path,
+            Some(dir.join("journal.jsonl")),
+            Some(dir.join("lib")),
+            false,
+            options,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn check_destinations_allows_only_vacated_and_unclaimed_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = write(&dir.path().join("a"), "a");
+        let b = write(&dir.path().join("b"), "b");
+        let c = write(&dir.path().join("c"), "c");
+        let x = dir.path().join("x");
+        // A chain into vacated names
+        let chain = plan(&[
+            (Action::Move, &a, &b),
+            (Action::Move, &b, &c),
+            (Action::Delete, &c, &c),
+        ]);
+        assert!(check_destinations(&chain).is_empty());
+        // Two files to one name, an existing name and a swap
+        let taken = plan(&[
+            (Action::Copy, &a, &x),
+            (Action::Copy, &b, &x),
+            (Action::Copy, &c, &a),
+        ]);
+        assert_eq!(check_destinations(&taken).len(), 2);
+        let swap = plan(&[(Action::Move, &a, &b), (Action::Move, &b, &a)]);
+        assert_eq!(check_destinations(&swap).len(), 2);
+    }
+
+    #[test]
+    fn apply_plan_runs_the_plan_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        let a = write(&lib.join("a.jpg"), "a");
+        let b = write(&lib.join("b.jpg"), "b");
+        let new = write(&dir.path().join("in/new.jpg"), "new");
+        let entries = plan(&[
+            (Action::Delete, &b, &b),
+            (Action::Move, &a, &b),
+            (Action::Copy, &new, &a),
+        ]);
+        assert!(apply(dir.path(), &entries));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "a");
+        assert!(new.exists());
+        assert_eq!(
+            read_journal(&dir.path().join("journal.jsonl"))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn apply_plan_changes_nothing_when_an_entry_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = write(&dir.path().join("in/a.jpg"), "a");
+        let b = write(&dir.path().join("in/b.jpg"), "b");
+        let dest = dir.path().join("lib/IMG.jpg");
+        let colliding = plan(&[(Action::Copy, &a, &dest), (Action::Copy, &b, &dest)]);
+        assert!(!apply(dir.path(), &colliding));
+        assert!(!dest.exists());
+
+        let mut changed = plan(&[(Action::Move, &a, &dest)]);
+        fs::write(&a, "changed").unwrap();
+        assert!(!apply(dir.path(), &changed));
+        changed[0].hash.clear();
+        assert!(!apply(dir.path(), &changed));
+        assert!(a.exists() && !dest.exists());
+    }
+
+    #[test]
+    fn process_actions_stops_when_the_journal_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = write(&dir.path().join("lib/a"), "a");
+        let b = write(&dir.path().join("lib/b"), "b");
+        let c = dir.path().join("lib/c");
+        let journals = dir.path().join("journals");
+        let journal = Journal::create(&journals.join("run.jsonl")).unwrap();
+        fs::remove_dir(&journals).unwrap();
+        let options = TransferOptions {
+            verify_copies: false,
+            preserve: Vec::new(),
+            mtime_from_metadata: false,
+        };
+        let report = RunReport::default();
+        // b leaves for c first, then a takes its name
+        let actions = vec![
+            (Action::Move, a.clone(), b.clone()),
+            (Action::Move, b.clone(), c.clone()),
+        ];
+        let completed =
+            process_actions(actions, &HashMap::new(), &journal, None, &options, &report);
+        assert_eq!(completed.len(), 1);
+        assert!(a.exists() && c.exists() && !b.exists());
+        assert!(report.has_errors());
+    }
+
+    #[test]
+    fn documents_are_dated_by_their_local_modification_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let doc = write(&dir.path().join("late.pdf"), "pdf");
+        let modified = DateTime::parse_from_rfc3339("2023-12-31T23:30:00Z").unwrap();
+        filetime::set_file_mtime(
+            &doc,
+            filetime::FileTime::from_unix_time(modified.timestamp(), 0),
+        )
+        .unwrap();
+        let local = modified.with_timezone(&Local).naive_local();
+        assert_eq!(document_datetime(&doc).unwrap().naive_utc(), local);
+    }
+
+    /// A JPEG whose EXIF only holds the original datetime, `YYYY:MM:DD HH:MM:SS`.
+    fn exif_jpeg(datetime: &str) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        // IFD0 with only the EXIF IFD pointer, then the EXIF IFD with the datetime
+        for (tag, value_type, count, value) in [(0x8769u16, 4u16, 1u32, 26u32), (0x9003, 2, 20, 44)]
+        {
+            tiff.extend(1u16.to_le_bytes());
+            tiff.extend(tag.to_le_bytes());
+            tiff.extend(value_type.to_le_bytes());
+            tiff.extend(count.to_le_bytes());
+            tiff.extend(value.to_le_bytes());
+            tiff.extend(0u32.to_le_bytes());
+        }
+        tiff.extend(datetime.as_bytes());
+        tiff.push(0);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend((8 + tiff.len() as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend([0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        return jpeg;
+    }
+
+    fn parser_coverage(report: &RunReport, dir: &Path) -> serde_json::Value {
+        let path = dir.join("report.json");
+        report.write_json(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        return json["parsers"].clone();
+    }
+
+    #[test]
+    fn cached_files_count_towards_parser_coverage() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join("dated.jpg"), exif_jpeg("2023:05:24 14:03:11")).unwrap();
+        fs::write(lib.join("plain.jpg"), [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let exts = Config::default_file_exts();
+
+        let first = RunReport::default();
+        let files = scan_files(vec![lib.clone()], &exts, true, None, &first);
+        let mut cache = IndexCache::empty(&dir.path().join("index.json"));
+        cache.replace_entries(files.into_iter().collect());
+        let cached = RunReport::default();
+        scan_files(vec![lib], &exts, true, Some(&cache), &cached);
+
+        let coverage = parser_coverage(&first, dir.path());
+        assert_eq!(coverage["EXIF"]["dated"], 1);
+        assert_eq!(parser_coverage(&cached, dir.path()), coverage);
+    }
+
+    #[test]
+    fn deleted_files_are_moved_to_the_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        let trash = Trash::new(&lib);
+        let journal = Journal::create(&dir.path().join("journal.jsonl")).unwrap();
+        let options = TransferOptions {
+            verify_copies: false,
+            preserve: Vec::new(),
+            mtime_from_metadata: false,
+        };
+        let report = RunReport::default();
+        let delete = |content: &str| {
+            let file = write(&lib.join("img/a.jpg"), content);
+            let actions = vec![(Action::Delete, file.clone(), file.clone())];
+            let hashes = HashMap::new();
+            let completed =
+                process_actions(actions, &hashes, &journal, Some(&trash), &options, &report);
+            assert!(!file.exists());
+            return completed[0].2.clone();
+        };
+
+        let trashed = delete("a");
+        assert_eq!(trashed, trash.trash_path(&lib.join("img/a.jpg")));
+        // The same file deleted again today is already in the trash
+        assert_eq!(delete("a"), trashed);
+        let other = delete("different");
+        assert_ne!(other, trashed);
+        assert_eq!(fs::read_to_string(&trashed).unwrap(), "a");
+        assert_eq!(fs::read_to_string(&other).unwrap(), "different");
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn number_sequences_continues_after_earlier_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        let template = lib.join("IMG_{seq:03}.jpg");
+        let numbered = write(&lib.join("IMG_001.jpg"), "1");
+        write(&lib.join("IMG_002.jpg"), "2");
+        let run = |sources: &[&str], library: &[&PathBuf]| {
+            let actions = sources
+                .iter()
+                .map(|name| {
+                    (
+                        Action::Copy,
+                        write(&dir.path().join(name), name),
+                        template.clone(),
+                    )
+                })
+                .chain(
+                    library
+                        .iter()
+                        .map(|src| (Action::Move, src.to_path_buf(), template.clone())),
+                )
+                .collect();
+            let mut actions = number_sequences(actions, &HashMap::new());
+            actions.sort_by(|(_, src_a, _), (_, src_b, _)| src_a.cmp(src_b));
+            for (_, _, dest) in &actions {
+                write(dest, "new");
+            }
+            return actions
+                .into_iter()
+                .map(|(_, src, dest)| (src, dest.file_name().unwrap().to_owned()))
+                .collect::<Vec<_>>();
+        };
+
+        // Numbered library files stay, new files continue after the highest number
+        assert_eq!(
+            run(&["in/b.jpg", "in/a.jpg"], &[&numbered]),
+            [
+                (dir.path().join("in/a.jpg"), "IMG_003.jpg".into()),
+                (dir.path().join("in/b.jpg"), "IMG_004.jpg".into()),
+            ]
+        );
+        assert_eq!(
+            run(&["in/c.jpg"], &[]),
+            [(dir.path().join("in/c.jpg"), "IMG_005.jpg".into())]
+        );
+    }
 }
@@ -0,0 +1,473 @@
+use std::path::PathBuf;
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Datelike, TimeZone, Timelike, Utc,
+};
+
+/// Stands in for a field the file has no value for.
+const UNKNOWN: &str = "unknown";
+
+/// The fields a layout template can refer to.
+const LAYOUT_FIELDS: &[&str] = &[
+    "year",
+    "month",
+    "month_name",
+    "day",
+    "camera_make",
+    "camera_model",
+];
+/// The fields a name template can refer to.
+const NAME_FIELDS: &[&str] = &[
+    "year",
+    "month",
+    "month_name",
+    "day",
+    "hour",
+    "minute",
+    "second",
+    "subsec",
+    "stem",
+    "camera_make",
+    "camera_model",
+    "type",
+    "prefix",
+    "hash",
+    "seq",
+];
+/// Fields that take a zero padded width, e.g. `{month:02}`.
+const NUMERIC_FIELDS: &[&str] = &["year", "month", "day", "hour", "minute", "second", "seq"];
+/// Characters that are not allowed in file names on at least one common file system.
+const ILLEGAL_CHARS: &[char] = &['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+const MAX_NAME_BYTES: usize = 255;
+/// Widest zero padding of a numeric field, more digits than any date part or count has.
+const MAX_WIDTH: usize = 10;
+
+/// What the fields of a template are filled in with for one file.
+#[derive(Default)]
+pub struct TemplateValues<'a> {
+    pub datetime: Option<DateTime<Utc>>,
+    pub camera_make: Option<&'a str>,
+    pub camera_model: Option<&'a str>,
+    /// The original file name without its extension.
+    pub stem: Option<&'a str>,
+    /// `image` or `video`.
+    pub media_type: Option<&'a str>,
+    /// `IMG` or `VID`.
+    pub prefix: Option<&'a str>,
+    /// Full content hash, only needed when the template uses `{hash}`.
+    pub hash: Option<u64>,
+}
+
+enum Part<'a> {
+    Literal(&'a str),
+    Field { name: &'a str, width: Option<usize> },
+}
+
+/// Splits a template into literal text and `{field}` or `{field:0N}` placeholders.
+fn parse<'a>(template: &'a str, fields: &[&str]) -> Result<Vec<Part<'a>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("`{}` has an unmatched `}}`", template));
+        }
+        if start > 0 {
+            parts.push(Part::Literal(&rest[..start]));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("`{}` has an unclosed `{{`", template))?
+            + start;
+        let placeholder = &rest[start + 1..end];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => (name, Some(width)),
+            None => (placeholder, None),
+        };
+        if !fields.contains(&name) {
+            return Err(format!(
+                "unknown field `{{{}}}`, expected one of {}",
+                name,
+                fields.join(", ")
+            ));
+        }
+        let width = match width {
+            Some(width) => Some(parse_width(name, width)?),
+            None => None,
+        };
+        parts.push(Part::Field { name, width });
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest));
+    }
+    return Ok(parts);
+}
+
+/// The width of numeric fields pads them with zeros, for `{subsec}` and `{hash}` it is the
+/// number of digits kept.
+fn parse_width(name: &str, width: &str) -> Result<usize, String> {
+    let max = match name {
+        "subsec" => 9,
+        "hash" => 16,
+        _ if NUMERIC_FIELDS.contains(&name) => MAX_WIDTH,
+        _ => return Err(format!("`{{{}}}` takes no width", name)),
+    };
+    return match width.parse::<usize>() {
+        Ok(width) if (1..=max).contains(&width) => Ok(width),
+        _ => Err(format!("`{{{}:{}}}` has an invalid width", name, width)),
+    };
+}
+
+/// Checks a destination folder layout such as `{year}/{month:02}-{month_name}`. It has to stay
+/// below the destination folder, so it may not be absolute or contain `.` or `..` folders. The
+/// layout is tried on a sample file, so layouts giving folder names that are illegal on common
+/// file systems are rejected.
+pub fn validate_layout(template: &str) -> Result<(), String> {
+    parse(template, LAYOUT_FIELDS)?;
+    if template.starts_with('/') || template.contains('\\') {
+        return Err(format!("`{}` must be a relative path using `/`", template));
+    }
+    if template
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(format!(
+            "`{}` must not contain `.` or `..` folders",
+            template
+        ));
+    }
+    let folders = render_layout(template, &sample_values());
+    for folder in &folders {
+        check_file_name(&folder.to_string_lossy()).map_err(|e| {
+            format!(
+                "`{}` gives folders like `{}`, which {}",
+                template,
+                folders.display().to_string().escape_debug(),
+                e
+            )
+        })?;
+    }
+    return Ok(());
+}
+
+/// The folder a file goes to below its destination folder. An empty layout keeps files
+/// directly in the destination folder, a field the file has no value for becomes `unknown`.
+pub fn render_layout(template: &str, values: &TemplateValues) -> PathBuf {
+    let parts = parse(template, LAYOUT_FIELDS).expect("layout is validated up front");
+    let mut rendered = String::new();
+    for part in parts {
+        match part {
+            Part::Literal(text) => rendered.push_str(text),
+            Part::Field { name, width } => {
+                rendered.push_str(&sanitize(field_value(name, width, values)))
+            }
+        }
+    }
+    return rendered
+        .split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect();
+}
+
+/// Checks a name template such as `{prefix}_%Y%m%d_%H%M%S` or
+/// `{year}-{month:02}-{day:02} {hour:02}.{minute:02}.{second:02} ({camera_model})`.
+/// Text outside the fields may use `strftime` specifiers. The template is tried on a sample
+/// file, so templates giving names that are illegal on common file systems are rejected.
+pub fn validate_name_template(template: &str) -> Result<(), String> {
+    if template.trim().is_empty() {
+        return Err("must not be empty".to_owned());
+    }
+    for part in parse(template, NAME_FIELDS)? {
+        if let Part::Literal(text) = part {
+            if StrftimeItems::new(text).any(|item| item == Item::Error) {
+                return Err(format!(
+                    "`{}` contains an invalid format specifier",
+                    template
+                ));
+            }
+        }
+    }
+    let name = fill_sequence(&render_name(template, &sample_values(), "jpg"), 1);
+    return check_file_name(&name).map_err(|e| {
+        format!(
+            "`{}` gives names like `{}`, which {}",
+            template,
+            name.escape_debug(),
+            e
+        )
+    });
+}
+
+/// A file with every field, to try templates on.
+fn sample_values() -> TemplateValues<'static> {
+    return TemplateValues {
+        datetime: Utc.with_ymd_and_hms(2023, 5, 24, 14, 3, 11).single(),
+        camera_make: Some("Google"),
+        camera_model: Some("Pixel 7"),
+        stem: Some("PXL_0001"),
+        media_type: Some("image"),
+        prefix: Some("IMG"),
+        hash: Some(0),
+    };
+}
+
+/// The new name of a dated file. `{seq}` is left in the name to be numbered once all files
+/// are known, see `fill_sequence`.
+pub fn render_name(template: &str, values: &TemplateValues, ext: &str) -> String {
+    let parts = parse(template, NAME_FIELDS).expect("name template is validated up front");
+    let mut name = String::new();
+    for part in parts {
+        match part {
+            Part::Literal(text) => match values.datetime {
+                Some(dt) => name.push_str(&dt.format(text).to_string()),
+                None => name.push_str(text),
+            },
+            Part::Field { name: "seq", width } => match width {
+                Some(width) => name.push_str(&format!("{{seq:{:02}}}", width)),
+                None => name.push_str("{seq}"),
+            },
+            Part::Field { name: field, width } => {
+                name.push_str(&sanitize(field_value(field, width, values)))
+            }
+        }
+    }
+    return format!("{}.{}", name, ext);
+}
+
+/// Whether the template refers to the field, for fields that are costly to fill in.
+pub fn uses_field(template: &str, field: &str) -> bool {
+    return parse(template, NAME_FIELDS).is_ok_and(|parts| {
+        parts
+            .iter()
+            .any(|part| matches!(part, Part::Field { name, .. } if *name == field))
+    });
+}
+
+/// Whether the name still has a `{seq}` to be numbered.
+pub fn has_sequence(name: &str) -> bool {
+    return name.contains("{seq");
+}
+
+/// Replaces `{seq}` in a rendered name. Field values never contain braces, so any left are
+/// the sequence.
+pub fn fill_sequence(name: &str, seq: u64) -> String {
+    return match parse(name, &["seq"]) {
+        Ok(parts) => parts
+            .into_iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.to_owned(),
+                Part::Field { width, .. } => format!("{:0width$}", seq, width = width.unwrap_or(0)),
+            })
+            .collect(),
+        Err(_) => name.to_owned(),
+    };
+}
+
+/// The number a name got from `fill_sequence`, if it is `name` with its `{seq}` filled in.
+pub fn sequence_number(name: &str, filled: &str) -> Option<u64> {
+    let parts = parse(name, &["seq"]).ok()?;
+    let prefix = match parts.first()? {
+        Part::Literal(text) => *text,
+        Part::Field { .. } => "",
+    };
+    let digits = filled.strip_prefix(prefix)?;
+    let len = digits.len()
+        - digits
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .len();
+    // The text after the number may start with digits too, so every length is tried
+    return (1..=len.min(19)).rev().find_map(|len| {
+        let seq = digits[..len].parse().ok()?;
+        return match fill_sequence(name, seq) == filled {
+            true => Some(seq),
+            false => None,
+        };
+    });
+}
+
+/// Rejects names that cannot be created on every common file system.
+pub fn check_file_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("is not a file name".to_owned());
+    }
+    if name.len() > MAX_NAME_BYTES {
+        return Err(format!("is longer than {} bytes", MAX_NAME_BYTES));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| ILLEGAL_CHARS.contains(c) || c.is_control())
+    {
+        return Err(format!("contains `{}`", c.escape_debug()));
+    }
+    if name.ends_with(['.', ' ']) {
+        return Err("ends with a dot or space".to_owned());
+    }
+    return Ok(());
+}
+
+fn field_value(name: &str, width: Option<usize>, values: &TemplateValues) -> Option<String> {
+    let datetime = values.datetime;
+    let pad = width.unwrap_or(0);
+    return match name {
+        "year" => datetime.map(|dt| format!("{:0pad$}", dt.year())),
+        "month" => datetime.map(|dt| format!("{:0pad$}", dt.month())),
+        "month_name" => datetime.map(|dt| dt.format("%B").to_string()),
+        "day" => datetime.map(|dt| format!("{:0pad$}", dt.day())),
+        "hour" => datetime.map(|dt| format!("{:0pad$}", dt.hour())),
+        "minute" => datetime.map(|dt| format!("{:0pad$}", dt.minute())),
+        "second" => datetime.map(|dt| format!("{:0pad$}", dt.second())),
+        "subsec" => datetime.map(|dt| {
+            let digits = format!("{:09}", dt.nanosecond() % 1_000_000_000);
+            digits[..width.unwrap_or(3)].to_owned()
+        }),
+        "stem" => values.stem.map(str::to_owned),
+        "camera_make" => values.camera_make.map(str::to_owned),
+        "camera_model" => values.camera_model.map(str::to_owned),
+        "type" => values.media_type.map(str::to_owned),
+        "prefix" => values.prefix.map(str::to_owned),
+        "hash" => values
+            .hash
+            .map(|hash| format!("{:016x}", hash)[..width.unwrap_or(8)].to_owned()),
+        _ => unreachable!("fields are checked when parsing"),
+    };
+}
+
+/// Keeps a value within one file or folder name: braces, separators and characters that are
+/// illegal in names become `_`, and a value that ends up empty or as `.` or `..` is treated
+/// as missing.
+fn sanitize(value: Option<String>) -> String {
+    let value = value
+        .map(|value| {
+            value
+                .chars()
+                .map(|c| match c {
+                    '{' | '}' => '_',
+                    c if ILLEGAL_CHARS.contains(&c) || c.is_control() => '_',
+                    c => c,
+                })
+                .collect::<String>()
+        })
+        .map(|value| value.trim().to_owned());
+    return match value {
+        Some(value) if !value.is_empty() && value != "." && value != ".." => value,
+        _ => UNKNOWN.to_owned(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> TemplateValues<'static> {
+        return TemplateValues {
+            datetime: Utc.with_ymd_and_hms(2023, 5, 24, 14, 3, 11).single(),
+            camera_model: Some("Pixel 7"),
+            stem: Some("PXL_0001"),
+            prefix: Some("IMG"),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn parse_splits_literals_and_fields() {
+        let parts = parse("{year}-{month:02} x", NAME_FIELDS).unwrap();
+        assert_eq!(parts.len(), 4);
+        assert!(matches!(
+            parts[0],
+            Part::Field {
+                name: "year",
+                width: None
+            }
+        ));
+        assert!(matches!(parts[1], Part::Literal("-")));
+        assert!(matches!(
+            parts[2],
+            Part::Field {
+                name: "month",
+                width: Some(2)
+            }
+        ));
+        assert!(matches!(parts[3], Part::Literal(" x")));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_templates() {
+        assert!(parse("{year", NAME_FIELDS).is_err());
+        assert!(parse("year}", NAME_FIELDS).is_err());
+        assert!(parse("{colour}", NAME_FIELDS).is_err());
+        assert!(parse("{seq}", LAYOUT_FIELDS).is_err());
+        assert!(parse("{camera_model:02}", NAME_FIELDS).is_err());
+        assert!(parse("{subsec:10}", NAME_FIELDS).is_err());
+        assert!(parse("{month:0}", NAME_FIELDS).is_err());
+    }
+
+    #[test]
+    fn validate_name_template_rejects_oversized_widths() {
+        assert!(validate_name_template("{prefix}_{year}{month:02}_{seq:10}").is_ok());
+        assert!(validate_name_template("{year:100000}").is_err());
+        assert!(validate_name_template("{seq:11}").is_err());
+        assert!(validate_name_template("{hash:17}").is_err());
+    }
+
+    #[test]
+    fn render_name_fills_fields_and_strftime() {
+        let name = render_name(
+            "{prefix}_%Y%m%d_{hour:02}{minute:02} ({camera_model})",
+            &sample(),
+            "jpg",
+        );
+        assert_eq!(name, "IMG_20230524_1403 (Pixel 7).jpg");
+    }
+
+    #[test]
+    fn render_name_keeps_seq_and_sanitizes_values() {
+        let values = TemplateValues {
+            camera_model: Some("a/b {x}"),
+            ..sample()
+        };
+        assert_eq!(
+            render_name("{camera_model}_{camera_make}_{seq:03}", &values, "jpg"),
+            "a_b _x__unknown_{seq:03}.jpg"
+        );
+    }
+
+    #[test]
+    fn validate_layout_rejects_oversized_widths_and_illegal_folders() {
+        assert!(validate_layout("{year}/{month:02}-{month_name}").is_ok());
+        assert!(validate_layout("{year:10}").is_ok());
+        assert!(validate_layout("{year:70000}").is_err());
+        assert!(validate_layout("{year}/a:b").is_err());
+        assert!(validate_layout(&"a".repeat(256)).is_err());
+        assert!(validate_layout("{year}/../x").is_err());
+    }
+
+    #[test]
+    fn fill_sequence_pads_to_width() {
+        assert_eq!(fill_sequence("IMG_{seq}.jpg", 7), "IMG_7.jpg");
+        assert_eq!(fill_sequence("IMG_{seq:03}.jpg", 7), "IMG_007.jpg");
+        assert_eq!(fill_sequence("IMG_{seq:02}.jpg", 123), "IMG_123.jpg");
+    }
+
+    #[test]
+    fn sequence_number_reverses_fill_sequence() {
+        assert_eq!(sequence_number("IMG_{seq:03}.jpg", "IMG_042.jpg"), Some(42));
+        assert_eq!(sequence_number("IMG_{seq}.jpg", "IMG_1234.jpg"), Some(1234));
+        // The text after the number starts with a digit
+        assert_eq!(sequence_number("{seq}1.jpg", "121.jpg"), Some(12));
+        assert_eq!(sequence_number("IMG_{seq:03}.jpg", "IMG_42.jpg"), None);
+        assert_eq!(sequence_number("IMG_{seq}.jpg", "IMG_.jpg"), None);
+        assert_eq!(sequence_number("IMG_{seq}.jpg", "VID_1.jpg"), None);
+        assert_eq!(sequence_number("IMG_{seq}.jpg", "IMG_1.png"), None);
+    }
+
+    #[test]
+    fn check_file_name_rejects_unportable_names() {
+        assert!(check_file_name("IMG_0001.jpg").is_ok());
+        assert!(check_file_name("a:b.jpg").is_err());
+        assert!(check_file_name("name.").is_err());
+        assert!(check_file_name("..").is_err());
+        assert!(check_file_name(&"a".repeat(256)).is_err());
+    }
+}
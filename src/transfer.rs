@@ -0,0 +1,378 @@
+use std::{
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::{fchown, MetadataExt},
+    path::Path,
+    time::SystemTime,
+};
+
+use clap::ValueEnum;
+use filetime::FileTime;
+use log::debug;
+use serde::Deserialize;
+
+use crate::duplicates::files_byte_identical;
+
+/// What a copy takes over from its source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Preserve {
+    /// Access and modification times
+    Times,
+    /// Permission bits
+    Mode,
+    /// Owner and group, skipped without the privileges to change them
+    Owner,
+    /// Extended attributes in the `user.` namespace
+    Xattrs,
+}
+
+impl Preserve {
+    pub const ALL: &'static [Preserve] = &[
+        Preserve::Times,
+        Preserve::Mode,
+        Preserve::Owner,
+        Preserve::Xattrs,
+    ];
+}
+
+/// How a run copies and moves files.
+pub struct TransferOptions {
+    /// Compare copies with their source byte for byte before putting them in place.
+    pub verify_copies: bool,
+    /// What copies take over from their source.
+    pub preserve: Vec<Preserve>,
+    /// Set the modification time of copied and moved files to their metadata datetime.
+    pub mtime_from_metadata: bool,
+}
+
+/// Copies the file to its destination through a hidden temp file next to it. The temp file is
+/// synced and only then moved into place, so a crash or a full disk never leaves a truncated
+/// file under the final name. With `verify`, the copy is first compared with the source byte
+/// for byte, metadata included. `modified` replaces the modification time taken over from
+/// the source.
+pub fn copy_file(
+    src: &Path,
+    dest: &Path,
+    verify: bool,
+    preserve: &[Preserve],
+    modified: Option<SystemTime>,
+) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = dest.with_file_name(format!(
+        ".{}.sorter-tmp",
+        dest.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    // Never replaces a file that appeared at the destination in the meantime
+    let result = write_copy(src, &temp, verify, preserve, modified)
+        .and_then(|_| rename_exclusive(&temp, dest));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    if let Some(parent) = dest.parent() {
+        let _ = File::open(parent).and_then(|dir| dir.sync_all());
+    }
+    return result;
+}
+
+fn write_copy(
+    src: &Path,
+    temp: &Path,
+    verify: bool,
+    preserve: &[Preserve],
+    modified: Option<SystemTime>,
+) -> io::Result<()> {
+    let mut reader = File::open(src)?;
+    let mut writer = File::create(temp)?;
+    let written = io::copy(&mut reader, &mut writer)?;
+    let metadata = reader.metadata()?;
+    if written != metadata.len() {
+        return Err(io::Error::other(format!(
+            "copied {} of {} bytes",
+            written,
+            metadata.len()
+        )));
+    }
+    preserve_metadata(src, &writer, &metadata, preserve, modified)?;
+    writer.sync_all()?;
+    // The content hash leaves out metadata, so the whole file is compared
+    if verify && !files_byte_identical(src, temp).map_err(|e| io::Error::other(e.to_string()))? {
+        return Err(io::Error::other("copy does not match the source"));
+    }
+    return Ok(());
+}
+
+/// Moves the file without ever replacing a file at the destination. Across file systems,
+/// where renaming is impossible, the file is copied and compared with the source byte for
+/// byte, and the source is only removed once the copy is in place.
+pub fn move_file(
+    src: &Path,
+    dest: &Path,
+    preserve: &[Preserve],
+    modified: Option<SystemTime>,
+) -> io::Result<()> {
+    match rename_exclusive(src, dest) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        Ok(()) => {
+            if let Some(modified) = modified {
+                filetime::set_file_mtime(dest, FileTime::from_system_time(modified))?;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    debug!(
+        "{}: {} is on another file system, copying",
+        src.display(),
+        dest.display()
+    );
+    copy_file(src, dest, true, preserve, modified)?;
+    if let Err(e) = fs::remove_file(src) {
+        // Undo the copy, a move is either done completely or not at all
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+    return Ok(());
+}
+
+/// Renames the file without ever replacing a file at the destination. A hard link claims the
+/// destination exclusively before the source is removed. Where links are not supported, the
+/// destination is checked right before renaming.
+fn rename_exclusive(src: &Path, dest: &Path) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::hard_link(src, dest) {
+        Ok(()) => {
+            if let Err(e) = fs::remove_file(src) {
+                let _ = fs::remove_file(dest);
+                return Err(e);
+            }
+            return Ok(());
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::AlreadyExists | io::ErrorKind::CrossesDevices
+            ) =>
+        {
+            return Err(e)
+        }
+        Err(_) => {}
+    }
+    if fs::symlink_metadata(dest).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        ));
+    }
+    return fs::rename(src, dest);
+}
+
+/// Takes over what `preserve` lists from the source. The times are set last, as the other
+/// changes could touch them.
+fn preserve_metadata(
+    src: &Path,
+    file: &File,
+    metadata: &Metadata,
+    preserve: &[Preserve],
+    modified: Option<SystemTime>,
+) -> io::Result<()> {
+    if preserve.contains(&Preserve::Mode) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    if preserve.contains(&Preserve::Owner) {
+        match fchown(file, Some(metadata.uid()), Some(metadata.gid())) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                debug!("{}: not allowed to keep its owner", src.display())
+            }
+            result => result?,
+        }
+    }
+    if preserve.contains(&Preserve::Xattrs) {
+        copy_user_xattrs(src, file)?;
+    }
+    let times = preserve.contains(&Preserve::Times);
+    if !times && modified.is_none() {
+        return Ok(());
+    }
+    let accessed = match times {
+        true => FileTime::from_last_access_time(metadata),
+        false => FileTime::now(),
+    };
+    let modified = match modified {
+        Some(modified) => FileTime::from_system_time(modified),
+        None => FileTime::from_last_modification_time(metadata),
+    };
+    return filetime::set_file_handle_times(file, Some(accessed), Some(modified));
+}
+
+/// Copies the `user.` extended attributes. File systems without extended attributes are
+/// skipped, as there is nothing to keep on them.
+fn copy_user_xattrs(src: &Path, file: &File) -> io::Result<()> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names.filter(|name| name.as_encoded_bytes().starts_with(b"user.")) {
+        let value = match xattr::get(src, &name)? {
+            Some(value) => value,
+            None => continue,
+        };
+        match xattr::FileExt::set_xattr(file, &name, &value) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                debug!(
+                    "{}: destination does not support extended attributes",
+                    src.display()
+                );
+                return Ok(());
+            }
+            result => result?,
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &str) -> std::path::PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        return path.to_path_buf();
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn copies_are_put_in_place_without_a_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("src/a.jpg"), "content");
+        let dest = dir.path().join("dest/2023/a.jpg");
+        for verify in [false, true] {
+            copy_file(&src, &dest, verify, &[], None).unwrap();
+            assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+            assert_eq!(names(dest.parent().unwrap()), ["a.jpg"]);
+            fs::remove_file(&dest).unwrap();
+        }
+        assert!(src.exists());
+    }
+
+    #[test]
+    fn copies_never_replace_an_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("a.jpg"), "new");
+        let dest = write(&dir.path().join("dest/a.jpg"), "existing");
+        let error = copy_file(&src, &dest, true, &[], None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "existing");
+        assert_eq!(names(dest.parent().unwrap()), ["a.jpg"]);
+    }
+
+    #[test]
+    fn moves_within_a_file_system_are_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("a.jpg"), "content");
+        let inode = fs::metadata(&src).unwrap().ino();
+        let dest = dir.path().join("dest/a.jpg");
+        move_file(&src, &dest, &[], None).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::metadata(&dest).unwrap().ino(), inode);
+        assert_eq!(fs::metadata(&dest).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn moves_never_replace_an_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("a.jpg"), "new");
+        let dest = write(&dir.path().join("dest/a.jpg"), "existing");
+        let error = move_file(&src, &dest, &[], None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&src).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "existing");
+    }
+
+    #[test]
+    fn moves_across_file_systems_copy_and_remove_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        // Memory backed, so usually another file system than the temp folder
+        let other = match tempfile::tempdir_in("/dev/shm") {
+            Ok(other) => other,
+            Err(_) => return,
+        };
+        let dev = |path: &Path| fs::metadata(path).unwrap().dev();
+        if dev(dir.path()) == dev(other.path()) {
+            return;
+        }
+        let src = write(&dir.path().join("a.jpg"), "content");
+        let dest = other.path().join("dest/a.jpg");
+        let error = rename_exclusive(&src, &dest).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::CrossesDevices);
+
+        move_file(&src, &dest, &[], None).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+        assert_eq!(names(dest.parent().unwrap()), ["a.jpg"]);
+    }
+
+    #[test]
+    fn copies_keep_only_what_is_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("a.jpg"), "content");
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o604)).unwrap();
+        let past = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_times(&src, past, past).unwrap();
+        let has_xattrs = xattr::set(&src, "user.comment", b"beach").is_ok();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let mtime =
+            |path: &Path| FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+
+        let all = dir.path().join("all.jpg");
+        copy_file(&src, &all, false, Preserve::ALL, None).unwrap();
+        assert_eq!(mode(&all), 0o604);
+        assert_eq!(mtime(&all), past);
+        if has_xattrs {
+            assert_eq!(xattr::get(&all, "user.comment").unwrap().unwrap(), b"beach");
+        }
+
+        let none = dir.path().join("none.jpg");
+        copy_file(&src, &none, false, &[], None).unwrap();
+        assert_ne!(mode(&none), 0o604);
+        assert_ne!(mtime(&none), past);
+        assert!(xattr::get(&none, "user.comment")
+            .unwrap_or_default()
+            .is_none());
+    }
+
+    #[test]
+    fn metadata_datetimes_replace_the_modification_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = write(&dir.path().join("a.jpg"), "content");
+        let taken = FileTime::from_unix_time(1_500_000_000, 0);
+        let mtime =
+            |path: &Path| FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+
+        let copy = dir.path().join("copy.jpg");
+        let modified = Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000));
+        copy_file(&src, &copy, false, Preserve::ALL, modified).unwrap();
+        assert_eq!(mtime(&copy), taken);
+        assert_ne!(mtime(&src), taken);
+
+        let moved = dir.path().join("moved.jpg");
+        move_file(&src, &moved, Preserve::ALL, modified).unwrap();
+        assert_eq!(mtime(&moved), taken);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{Duration, Local, NaiveDate};
+
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Deleted files are moved into `<library root>/.sorter/trash/<date>/`, keeping their path
+/// relative to the library root so they can be found (and restored) later.
+pub struct Trash {
+    library_root: PathBuf,
+    dir: PathBuf,
+}
+
+impl Trash {
+    pub fn new(library_root: &Path) -> Self {
+        return Trash {
+            library_root: library_root.to_path_buf(),
+            dir: trash_root(library_root).join(Local::now().format(DATE_FORMAT).to_string()),
+        };
+    }
+
+    pub fn trash_path(&self, src: &Path) -> PathBuf {
+        let relative: PathBuf = match src.strip_prefix(&self.library_root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => src
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect(),
+        };
+        return self.dir.join(relative);
+    }
+}
+
+pub fn trash_root(library_root: &Path) -> PathBuf {
+    return library_root.join(".sorter").join("trash");
+}
+
+/// Permanently removes trash folders older than the retention period, or all of them.
+pub fn purge_trash(library_root: &Path, retention_days: u32, all: bool) -> Result<bool, String> {
+    let root = trash_root(library_root);
+    if !root.is_dir() {
+        println!("Trash is empty");
+        return Ok(true);
+    }
+    let cutoff = Local::now().date_naive() - Duration::days(retention_days as i64);
+    let mut purged = 0;
+    let mut kept = 0;
+    let entries = fs::read_dir(&root).map_err(|e| format!("{}: {}", root.display(), e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("{}: {}", root.display(), e))?
+            .path();
+        let date = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| NaiveDate::parse_from_str(n, DATE_FORMAT).ok());
+        match date {
+            Some(date) if all || date < cutoff => {
+                fs::remove_dir_all(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                purged += 1;
+            }
+            Some(_) => kept += 1,
+            None => println!("Skipping unexpected trash entry {}", path.display()),
+        }
+    }
+    println!(
+        "Purged {} trash folders, kept {} newer than {} days",
+        purged, kept, retention_days
+    );
+    return Ok(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trash_paths_keep_the_path_below_the_library_root() {
+        let trash = Trash::new(Path::new("/library"));
+        let today = Local::now().format(DATE_FORMAT).to_string();
+        let day = Path::new("/library/.sorter/trash").join(today);
+        assert_eq!(
+            trash.trash_path(Path::new("/library/img/a.jpg")),
+            day.join("img/a.jpg")
+        );
+        assert_eq!(
+            trash.trash_path(Path::new("/elsewhere/../b.jpg")),
+            day.join("elsewhere/b.jpg")
+        );
+    }
+
+    #[test]
+    fn purge_removes_only_folders_past_the_retention() {
+        let library = tempfile::tempdir().unwrap();
+        let root = trash_root(library.path());
+        let day = |days: i64| {
+            let date = Local::now().date_naive() - Duration::days(days);
+            return root.join(date.format(DATE_FORMAT).to_string());
+        };
+        for dir in [day(0), day(29), day(31), root.join("notes")] {
+            fs::create_dir_all(dir.join("img")).unwrap();
+            fs::write(dir.join("img/a.jpg"), "a").unwrap();
+        }
+
+        assert!(purge_trash(library.path(), 30, false).unwrap());
+        assert!(day(0).exists() && day(29).exists() && !day(31).exists());
+        assert!(root.join("notes").exists());
+
+        assert!(purge_trash(library.path(), 30, true).unwrap());
+        assert!(!day(0).exists() && !day(29).exists());
+        assert!(root.join("notes").exists());
+    }
+}
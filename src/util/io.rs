@@ -1,9 +1,14 @@
 use std::{
     cmp::min,
-    io::{Read, Seek, SeekFrom},
+    io::{ErrorKind, Read, Seek, SeekFrom},
 };
 
-const CHUNK_SIZE: usize = 4096 as usize;
+use log::trace;
+
+use crate::error::{FileError, FileResult};
+
+const CHUNK_SIZE: usize = 4096;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct StepableBuffReader<R: Read> {
     reader: R,
@@ -16,12 +21,12 @@ pub struct StepableBuffReader<R: Read> {
 }
 
 impl<R: Read + Seek> StepableBuffReader<R> {
-    pub fn new(mut reader: R) -> Self {
+    pub fn new(mut reader: R) -> FileResult<Self> {
         let mut buf1 = [0; CHUNK_SIZE];
-        let buf1_size = reader.read(&mut buf1).unwrap();
+        let buf1_size = reader.read(&mut buf1)?;
         let mut buf2 = [0; CHUNK_SIZE];
-        let buf2_size = reader.read(&mut buf2).unwrap();
-        StepableBuffReader {
+        let buf2_size = reader.read(&mut buf2)?;
+        return Ok(StepableBuffReader {
             reader,
             buf1,
             buf1_size,
@@ -29,120 +34,137 @@ impl<R: Read + Seek> StepableBuffReader<R> {
             buf2_size,
             pointer: 0,
             total_offset: 0,
-        }
+        });
     }
 
     pub fn available(&self) -> usize {
         return (self.buf1_size - self.pointer) + self.buf2_size;
     }
 
-    pub fn peak(&mut self, num_bytes: usize) -> Vec<u8> {
+    pub fn peak(&mut self, num_bytes: usize) -> FileResult<Vec<u8>> {
         if num_bytes > CHUNK_SIZE {
-            panic!("Not cool 1")
+            return Err(FileError::Unsupported(format!(
+                "field of {} bytes is larger than the read buffer",
+                num_bytes
+            )));
         }
         if num_bytes > self.available() {
-            panic!("Oh rip")
+            return Err(FileError::Truncated);
         }
         let mut buffer = Vec::new();
         let flag = min(self.buf1_size - self.pointer, num_bytes);
         buffer.extend_from_slice(&self.buf1[self.pointer..self.pointer + flag]);
         buffer.extend_from_slice(&self.buf2[0..num_bytes - flag]);
-        return buffer;
+        return Ok(buffer);
     }
 
-    pub fn increment(&mut self) -> bool {
+    pub fn increment(&mut self) -> FileResult<bool> {
         return self.increment_by(1);
     }
 
-    pub fn increment_by(&mut self, num_bytes: usize) -> bool {
+    /// Moves forward, returning `false` when fewer bytes than that are left.
+    pub fn increment_by(&mut self, num_bytes: usize) -> FileResult<bool> {
         if num_bytes > CHUNK_SIZE {
-            self.load_from(self.total_offset + num_bytes);
-            return true;
-            // panic!("Not cool")
+            self.load_from(self.total_offset + num_bytes)?;
+            return Ok(true);
         }
         if num_bytes > self.available() {
-            return false;
+            return Ok(false);
         }
         self.total_offset += num_bytes;
         if self.pointer + num_bytes > self.buf1_size {
             self.pointer = self.pointer + num_bytes - self.buf1_size;
             self.buf1 = self.buf2;
             self.buf1_size = self.buf2_size;
-            self.buf2_size = self.reader.read(&mut self.buf2).unwrap();
+            self.buf2_size = self.reader.read(&mut self.buf2)?;
         } else {
-            self.pointer = self.pointer + num_bytes
+            self.pointer += num_bytes
         }
-        return true;
+        return Ok(true);
     }
 
-    fn load_from(&mut self, index: usize) {
-        self.reader.seek(SeekFrom::Start(index as u64)).unwrap();
-        self.buf1_size = self.reader.read(&mut self.buf1).unwrap();
-        self.buf2_size = self.reader.read(&mut self.buf2).unwrap();
+    fn load_from(&mut self, index: usize) -> FileResult<()> {
+        trace!("Seeking to offset {}", index);
+        self.reader.seek(SeekFrom::Start(index as u64))?;
+        self.buf1_size = self.reader.read(&mut self.buf1)?;
+        self.buf2_size = self.reader.read(&mut self.buf2)?;
         self.total_offset = index;
         self.pointer = 0;
+        return Ok(());
     }
 
-    pub fn read(&mut self, num_bytes: usize) -> Vec<u8> {
-        if num_bytes > self.available() {
-            panic!("Reached end of source");
-        }
-        let data = self.peak(num_bytes);
-        self.increment_by(num_bytes);
-        return data;
+    pub fn read(&mut self, num_bytes: usize) -> FileResult<Vec<u8>> {
+        let data = self.peak(num_bytes)?;
+        self.increment_by(num_bytes)?;
+        return Ok(data);
     }
 
-    pub fn read_u32(&mut self, big_endian: bool) -> u32 {
-        let buffer = self.read(4);
+    pub fn read_u32(&mut self, big_endian: bool) -> FileResult<u32> {
+        let buffer: [u8; 4] = self.read(4)?.try_into().unwrap();
         if big_endian {
-            return u32::from_be_bytes(buffer.try_into().unwrap());
+            return Ok(u32::from_be_bytes(buffer));
         }
-        return u32::from_le_bytes(buffer.try_into().unwrap());
+        return Ok(u32::from_le_bytes(buffer));
     }
 
-    pub fn read_to_end(&mut self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.buf1[self.pointer..]);
-        buffer.extend_from_slice(&self.buf2);
-        let _ = self.reader.read_to_end(&mut buffer);
+    /// Passes everything from the current position to `consume` in bounded chunks, so the
+    /// rest of the source never has to fit in memory.
+    pub fn stream_to_end<F: FnMut(&[u8])>(&mut self, mut consume: F) -> FileResult<()> {
+        consume(&self.buf1[self.pointer..self.buf1_size]);
+        consume(&self.buf2[..self.buf2_size]);
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => consume(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
         self.buf1_size = 0;
         self.buf2_size = 0;
-        return buffer;
+        return Ok(());
     }
 
-    pub fn compare_bytes(&mut self, bytes: Vec<u8>) -> bool {
-        if self.peak(bytes.len()) == bytes {
-            self.increment_by(bytes.len());
-            return true;
+    /// Moves past `bytes` if they come next. Running out of data is not a match.
+    pub fn compare_bytes(&mut self, bytes: Vec<u8>) -> FileResult<bool> {
+        if bytes.len() > self.available() {
+            return Ok(false);
+        }
+        if self.peak(bytes.len())? == bytes {
+            self.increment_by(bytes.len())?;
+            return Ok(true);
         }
-        return false;
+        return Ok(false);
     }
 
-    pub fn compare_endian_bytes(&mut self, mut bytes: Vec<u8>, big_endian: bool) -> bool {
+    pub fn compare_endian_bytes(
+        &mut self,
+        mut bytes: Vec<u8>,
+        big_endian: bool,
+    ) -> FileResult<bool> {
         if !big_endian {
             bytes = bytes.iter().rev().cloned().collect();
         }
-        if self.peak(bytes.len()) == bytes {
-            self.increment_by(bytes.len());
-            return true;
-        }
-        return false;
+        return self.compare_bytes(bytes);
     }
 
-    pub fn compare_multiple_bytes(&mut self, bytes_list: Vec<Vec<u8>>) -> bool {
+    pub fn compare_multiple_bytes(&mut self, bytes_list: Vec<Vec<u8>>) -> FileResult<bool> {
         for bytes in bytes_list {
-            if self.compare_bytes(bytes) {
-                return true;
+            if self.compare_bytes(bytes)? {
+                return Ok(true);
             }
         }
-        return false;
+        return Ok(false);
     }
 
-    pub fn read_from(&mut self, offset: usize, length: usize) -> Vec<u8> {
-        if offset + length - self.total_offset > self.available() {
-            panic!("rip")
-        }
-        self.increment_by(&offset - &self.total_offset);
-        return self.read(length);
+    /// Reads `length` bytes at `offset`, which may lie behind the current position, and
+    /// returns to where it was.
+    pub fn read_at(&mut self, offset: usize, length: usize) -> FileResult<Vec<u8>> {
+        let position = self.total_offset;
+        self.load_from(offset)?;
+        let data = self.read(length);
+        self.load_from(position)?;
+        return data;
     }
 }
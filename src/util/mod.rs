@@ -1,4 +1,5 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
+use log::warn;
 
 pub mod io;
 
@@ -25,11 +26,10 @@ pub fn parse_datetime(input: &str) -> Option<DateTime<Utc>> {
         "%a %b %d %H:%M:%S %Y",
     ];
     for fmt in popular_fmts {
-        match NaiveDateTime::parse_from_str(&input, &fmt) {
-            Ok(dt) => return Some(dt.and_local_timezone(Utc).unwrap()),
-            Err(_) => (),
-        };
+        if let Ok(dt) = NaiveDateTime::parse_from_str(input, fmt) {
+            return Some(dt.and_local_timezone(Utc).unwrap());
+        }
     }
-    println!("Could not parse datetime ({})", input);
+    warn!("Could not parse datetime ({})", input);
     return None;
 }